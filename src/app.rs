@@ -1,24 +1,32 @@
-use std::{error::Error, time::Duration};
+use std::{error::Error, sync::Arc, time::Duration};
 
 use tokio::{
-  sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+  sync::mpsc::{self, UnboundedSender},
   task,
   time::interval,
 };
 
 use crate::{
-  storage::{DataBaseStorage, RaftNode},
+  config::Config,
+  state::{NodeInfo, State},
+  storage::{DatabaseStorage, RaftNode},
   tcp::server::TcpServer,
 };
 
 pub struct App {
+  node: Arc<NodeInfo>,
   tcp: TcpServer,
 }
 
 impl App {
-  fn spawn_raft_statemachine(storage: DataBaseStorage, sender: UnboundedSender<()>) {
+  /// Ticks the Raft node and keeps the role `node` reports to Ping up to date.
+  fn spawn_raft_statemachine(
+    node: Arc<NodeInfo>,
+    storage: DatabaseStorage,
+    sender: UnboundedSender<()>,
+  ) {
     task::spawn(async move {
-      let config = raft::Config { id: rand::random(), ..Default::default() };
+      let config = raft::Config { id: node.id, ..Default::default() };
 
       config.validate().unwrap();
 
@@ -38,23 +46,30 @@ impl App {
           }
           Err(err) => tracing::error!("Raft state machine error: {:?}", err),
         }
+        node.set_raft_role(Some(raft_node.role()));
       }
     });
   }
-  fn new() -> Result<App, Box<dyn Error>> {
-    let storage = DataBaseStorage::default();
-    let tcp = TcpServer::new("0.0.0.0:8080", state);
 
-    Ok(Self { tcp })
+  /// Call [State::init] first, so the node id is known.
+  pub fn new(config: &Config, state: State) -> Result<App, Box<dyn Error>> {
+    let node = state.node.clone();
+    let tcp = TcpServer::from_config(config, state)?;
+
+    Ok(Self { node, tcp })
   }
 
   pub async fn run(&mut self) {
     let (sender, mut receiver) = mpsc::unbounded_channel::<()>();
-    App::spawn_raft_statemachine(sender);
+    App::spawn_raft_statemachine(self.node.clone(), DatabaseStorage::default(), sender);
+
+    task::spawn(async move {
+      while let Some(value) = receiver.recv().await {
+        // TODO Handle value here
+        let _: () = value;
+      }
+    });
 
-    while let Some(value) = receiver.recv().await {
-      // TODO Handle value here
-      let _: () = value;
-    }
+    self.tcp.run().await;
   }
 }
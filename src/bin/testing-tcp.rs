@@ -1,7 +1,7 @@
 use std::io;
 
 use memory_db::{
-  public_api::dataquery::{PutQuery, ReadQuery},
+  public_api::dataquery::{PlainPutQuery, PutResponse, ReadQuery, ReadResponse},
   tcp::protocol::{RawRequest, RawResponse},
 };
 use tokio::net::TcpStream;
@@ -11,9 +11,9 @@ async fn send(cmd: u8, body: Vec<u8>) -> io::Result<RawResponse> {
   let mut stream = TcpStream::connect(address).await?;
 
   let req = RawRequest::new(cmd, body);
-  req.write_to_tcp_stream(&mut stream).await.unwrap();
+  req.write_to(&mut stream).await.unwrap();
 
-  let response = RawResponse::from_tcp_stream(&mut stream).await.unwrap();
+  let response = RawResponse::read_from(&mut stream).await.unwrap();
   Ok(response)
 }
 
@@ -21,27 +21,36 @@ async fn send(cmd: u8, body: Vec<u8>) -> io::Result<RawResponse> {
 async fn main() -> io::Result<()> {
   let response = send(
     2,
-    bincode::serialize(&PutQuery { key: "test2".to_string(), value: b"hello".to_vec() }).unwrap(),
+    bincode::serialize(&PlainPutQuery { key: "test2".to_string(), value: b"hello".to_vec() })
+      .unwrap(),
   )
   .await?;
 
   println!("Raw Response: {response:?}");
-  println!("Response body String: {}", String::from_utf8(response.body).unwrap());
+  let put: PutResponse = bincode::deserialize(&response.body).unwrap();
+  println!("Put version: {}", put.version);
 
   let response = send(
     2,
-    bincode::serialize(&PutQuery { key: "test".to_string(), value: b"hello".to_vec() }).unwrap(),
+    bincode::serialize(&PlainPutQuery { key: "test".to_string(), value: b"hello".to_vec() })
+      .unwrap(),
   )
   .await?;
 
   println!("Raw Response: {response:?}");
-  println!("Response body String: {}", String::from_utf8(response.body).unwrap());
+  let put: PutResponse = bincode::deserialize(&response.body).unwrap();
+  println!("Put version: {}", put.version);
 
   let response =
     send(1, bincode::serialize(&ReadQuery { key: "test".to_string() }).unwrap()).await?;
 
   println!("Raw Response: {response:?}");
-  println!("Response body String: {}", String::from_utf8(response.body).unwrap());
+  let read: ReadResponse = bincode::deserialize(&response.body).unwrap();
+  println!(
+    "Response value String: {} (version {})",
+    String::from_utf8(read.value).unwrap(),
+    read.version
+  );
 
   Ok(())
 }
@@ -0,0 +1,323 @@
+//! Blocking client for code that doesn't run in a tokio runtime. It mirrors the async
+//! [crate::client::Client] and speaks the same protocol over [std::net::TcpStream] or a Unix
+//! domain socket.
+
+use std::{
+  io::{self, Read, Write},
+  net::{TcpStream, ToSocketAddrs as _},
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Condvar, Mutex, PoisonError,
+  },
+  thread::sleep,
+  time::Duration,
+};
+
+use tokio_rustls::rustls::{ClientConnection, StreamOwned};
+
+use super::{
+  auth_request,
+  call::{self, Call, Retries},
+  check_id,
+  pool::Endpoint,
+  response_body, ClientError, DEFAULT_MAX_CONNECTIONS, DEFAULT_RETRIES, DEFAULT_RETRY_BACKOFF,
+  DEFAULT_TIMEOUT,
+};
+use crate::{
+  public_api::{
+    auth::AuthQuery,
+    dataquery::{CasQuery, DeleteQuery, DeleteResponse, PutQuery, ReadResponse},
+    ping::PingResponse,
+    scan::{ScanQuery, ScanResponse},
+  },
+  tcp::{
+    protocol::{RawRequest, RawResponse},
+    server::CommandV0,
+    tls::ClientTls,
+  },
+};
+
+/// See [crate::client::Client], this behaves the same but blocks the calling thread.
+#[derive(Clone)]
+pub struct Client {
+  pool: Arc<Pool>,
+  endpoint: Endpoint,
+  max_connections: usize,
+  credentials: Option<AuthQuery>,
+  next_id: Arc<AtomicU32>,
+  timeout: Duration,
+  retries: u32,
+  retry_backoff: Duration,
+}
+
+impl Client {
+  /// Doesn't connect yet, connections are opened on demand.
+  pub fn new(address: &str) -> Self {
+    Client::with_endpoint(Endpoint::Tcp(address.to_string()))
+  }
+
+  /// Client for a [crate::tcp::server::TcpServer] with TLS, see
+  /// [crate::tcp::tls::ClientTlsConfig::load].
+  pub fn new_tls(address: &str, tls: ClientTls) -> Self {
+    Client::with_endpoint(Endpoint::Tls(address.to_string(), tls))
+  }
+
+  /// Client for a [crate::tcp::unix_server::UnixServer] listening on `path`.
+  #[cfg(unix)]
+  pub fn new_unix(path: impl Into<std::path::PathBuf>) -> Self {
+    Client::with_endpoint(Endpoint::Unix(path.into()))
+  }
+
+  fn with_endpoint(endpoint: Endpoint) -> Self {
+    Client {
+      pool: Arc::new(Pool::new(endpoint.clone(), DEFAULT_MAX_CONNECTIONS, None)),
+      endpoint,
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      credentials: None,
+      next_id: Arc::new(AtomicU32::new(0)),
+      timeout: DEFAULT_TIMEOUT,
+      retries: DEFAULT_RETRIES,
+      retry_backoff: DEFAULT_RETRY_BACKOFF,
+    }
+  }
+
+  /// Limits how many connections are open at the same time. Further requests wait for a free
+  /// connection.
+  pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+    self.max_connections = max_connections;
+    self.with_new_pool()
+  }
+
+  /// Authenticates every connection as `user`, for servers with an ACL.
+  pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+    self.credentials = Some(AuthQuery { user: user.to_string(), password: password.to_string() });
+    self.with_new_pool()
+  }
+
+  fn with_new_pool(mut self) -> Self {
+    self.pool =
+      Arc::new(Pool::new(self.endpoint.clone(), self.max_connections, self.credentials.clone()));
+    self
+  }
+
+  /// Time connecting, sending and receiving may each take.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+    self.retries = retries;
+    self.retry_backoff = backoff;
+    self
+  }
+
+  pub fn ping(&self) -> Result<PingResponse, ClientError> {
+    self.call(call::ping()?)
+  }
+
+  /// [None] if the key doesn't exist.
+  pub fn get(&self, key: &str) -> Result<Option<ReadResponse>, ClientError> {
+    self.call(call::get(key)?)
+  }
+
+  /// Returns the version of the written value.
+  pub fn put(&self, key: &str, value: Vec<u8>) -> Result<u64, ClientError> {
+    self.call(call::put(key, value)?)
+  }
+
+  /// Put with an expiry or `if_version` condition. Returns the version of the written value.
+  pub fn put_query(&self, query: PutQuery) -> Result<u64, ClientError> {
+    self.call(call::put_query(query)?)
+  }
+
+  pub fn delete(&self, key: &str) -> Result<DeleteResponse, ClientError> {
+    self.call(call::delete(key)?)
+  }
+
+  pub fn delete_query(&self, query: DeleteQuery) -> Result<DeleteResponse, ClientError> {
+    self.call(call::delete_query(query)?)
+  }
+
+  /// Whether the value was swapped.
+  pub fn cas(&self, query: CasQuery) -> Result<bool, ClientError> {
+    self.call(call::cas(query)?)
+  }
+
+  pub fn scan(&self, query: ScanQuery) -> Result<ScanResponse, ClientError> {
+    self.call(call::scan(query)?)
+  }
+
+  fn call<R>(&self, call: Call<R>) -> Result<R, ClientError> {
+    let mut retries = Retries::new(&call, self.retries, self.retry_backoff);
+    let result = loop {
+      match self.send(call.command, call.body.clone()) {
+        Err(err) => match retries.next(&err) {
+          Some(backoff) => {
+            tracing::debug!("Retrying {:?} after: {}", call.command, err);
+            sleep(backoff);
+          }
+          None => break Err(err),
+        },
+        result => break result,
+      }
+    };
+
+    (call.response)(result)
+  }
+
+  /// Sends the request once and returns the body of a successful response.
+  fn send(&self, command: CommandV0, body: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let request = RawRequest::new_v1(id, command.into(), body);
+
+    let mut conn = self.pool.get(self.timeout)?;
+
+    let response = match exchange(&mut conn.stream, &request) {
+      Ok(response) => response,
+      Err(err) => {
+        // The other idle connections are probably broken as well, e.g. the server restarted.
+        if err.is_connection_error() {
+          self.pool.clear();
+        }
+        return Err(err);
+      }
+    };
+
+    check_id(&response, id)?;
+    conn.release();
+
+    response_body(response)
+  }
+}
+
+/// Writes `request` and reads its response, with the same error mapping as the async client.
+fn exchange(
+  stream: &mut Box<dyn Stream>,
+  request: &RawRequest,
+) -> Result<RawResponse, ClientError> {
+  request.write_to_blocking(stream)?;
+  Ok(RawResponse::read_from_blocking(stream)?)
+}
+
+/// Blocking counterpart of [super::pool::Stream].
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
+
+/// Blocking counterpart of [super::pool::Pool].
+struct Pool {
+  endpoint: Endpoint,
+  credentials: Option<AuthQuery>,
+  idle: Mutex<Vec<Box<dyn Stream>>>,
+  max_connections: usize,
+  /// Number of connections in use.
+  in_use: Mutex<usize>,
+  freed: Condvar,
+}
+
+struct PooledConnection<'a> {
+  stream: Box<dyn Stream>,
+  _in_use: InUse<'a>,
+}
+
+/// Counts a connection as in use until dropped, like a semaphore permit.
+struct InUse<'a>(&'a Pool);
+
+impl Pool {
+  fn new(endpoint: Endpoint, max_connections: usize, credentials: Option<AuthQuery>) -> Self {
+    Pool {
+      endpoint,
+      credentials,
+      idle: Mutex::new(Vec::new()),
+      max_connections,
+      in_use: Mutex::new(0),
+      freed: Condvar::new(),
+    }
+  }
+
+  /// An idle connection, or a new one. Waits while `max_connections` are in use.
+  fn get(&self, timeout: Duration) -> Result<PooledConnection<'_>, ClientError> {
+    let in_use = self.in_use.lock().unwrap_or_else(PoisonError::into_inner);
+    let (mut in_use, wait) = self
+      .freed
+      .wait_timeout_while(in_use, timeout, |in_use| *in_use >= self.max_connections)
+      .unwrap_or_else(PoisonError::into_inner);
+    if wait.timed_out() {
+      return Err(ClientError::Timeout);
+    }
+    *in_use += 1;
+    drop(in_use);
+    let in_use = InUse(self);
+
+    let idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+    let stream = match idle {
+      Some(stream) => stream,
+      None => {
+        let mut stream = self.connect(timeout)?;
+        if let Some(credentials) = &self.credentials {
+          response_body(exchange(&mut stream, &auth_request(credentials)?)?)?;
+        }
+        stream
+      }
+    };
+
+    Ok(PooledConnection { stream, _in_use: in_use })
+  }
+
+  fn connect(&self, timeout: Duration) -> io::Result<Box<dyn Stream>> {
+    match &self.endpoint {
+      Endpoint::Tcp(address) => Ok(Box::new(connect_tcp(address, timeout)?)),
+      Endpoint::Tls(address, tls) => {
+        let stream = connect_tcp(address, timeout)?;
+        let conn = ClientConnection::new(tls.config.clone(), tls.server_name.clone())
+          .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Box::new(StreamOwned::new(conn, stream)))
+      }
+      #[cfg(unix)]
+      Endpoint::Unix(path) => {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Box::new(stream))
+      }
+    }
+  }
+
+  /// Drops all idle connections, e.g. after the server restarted.
+  fn clear(&self) {
+    self.idle.lock().unwrap_or_else(PoisonError::into_inner).clear();
+  }
+}
+
+/// Tries every address `address` resolves to, like [TcpStream::connect] does, and returns the
+/// error of the last one if none accepts.
+fn connect_tcp(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+  let mut last_err = None;
+  let stream = address.to_socket_addrs()?.find_map(|address| {
+    TcpStream::connect_timeout(&address, timeout).map_err(|err| last_err = Some(err)).ok()
+  });
+  let stream = stream.ok_or_else(|| {
+    last_err
+      .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Address didn't resolve"))
+  })?;
+
+  stream.set_nodelay(true)?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+  Ok(stream)
+}
+
+impl PooledConnection<'_> {
+  /// Puts the connection back into the pool, see [super::pool::PooledConnection::release].
+  fn release(self) {
+    let PooledConnection { stream, _in_use: in_use } = self;
+    in_use.0.idle.lock().unwrap_or_else(PoisonError::into_inner).push(stream);
+  }
+}
+
+impl Drop for InUse<'_> {
+  fn drop(&mut self) {
+    *self.0.in_use.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+    self.0.freed.notify_one();
+  }
+}
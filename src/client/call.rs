@@ -0,0 +1,111 @@
+//! What the async and the blocking client have in common: which command each method sends,
+//! whether it may be retried, and how its response is decoded. Only sending differs.
+
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::ClientError;
+use crate::{
+  public_api::{
+    dataquery::{
+      CasQuery, CasResponse, DeleteQuery, DeleteResponse, PlainDeleteQuery, PlainPutQuery,
+      PutQuery, PutResponse, ReadQuery, ReadResponse,
+    },
+    ping::PingResponse,
+    scan::{ScanQuery, ScanResponse},
+  },
+  tcp::{protocol::ResponseType, server::CommandV0},
+};
+
+/// A request ready to be sent, and how to turn its outcome into the result of the method.
+pub(super) struct Call<R> {
+  pub command: CommandV0,
+  pub body: Vec<u8>,
+  /// Whether running it twice does no harm, so it may be retried.
+  pub idempotent: bool,
+  pub response: fn(Result<Vec<u8>, ClientError>) -> Result<R, ClientError>,
+}
+
+impl<R> Call<R> {
+  fn new<Q: Serialize>(
+    command: CommandV0,
+    query: &Q,
+    idempotent: bool,
+    response: fn(Result<Vec<u8>, ClientError>) -> Result<R, ClientError>,
+  ) -> Result<Self, ClientError> {
+    Ok(Call { command, body: bincode::serialize(query)?, idempotent, response })
+  }
+}
+
+fn decode<R: DeserializeOwned>(body: Result<Vec<u8>, ClientError>) -> Result<R, ClientError> {
+  Ok(bincode::deserialize(&body?)?)
+}
+
+pub(super) fn ping() -> Result<Call<PingResponse>, ClientError> {
+  Call::new(CommandV0::Ping, &(), true, decode)
+}
+
+pub(super) fn get(key: &str) -> Result<Call<Option<ReadResponse>>, ClientError> {
+  Call::new(CommandV0::Get, &ReadQuery { key: key.to_string() }, true, |body| match body {
+    Err(ClientError::Server { r#type: ResponseType::NotFound, .. }) => Ok(None),
+    body => decode(body).map(Some),
+  })
+}
+
+pub(super) fn put(key: &str, value: Vec<u8>) -> Result<Call<u64>, ClientError> {
+  let query = PlainPutQuery { key: key.to_string(), value };
+  Call::new(CommandV0::Put, &query, true, |body| {
+    decode::<PutResponse>(body).map(|response| response.version)
+  })
+}
+
+pub(super) fn put_query(query: PutQuery) -> Result<Call<u64>, ClientError> {
+  let idempotent = query.if_version.is_none();
+  Call::new(CommandV0::PutWithOptions, &query, idempotent, |body| {
+    decode::<PutResponse>(body).map(|response| response.version)
+  })
+}
+
+pub(super) fn delete(key: &str) -> Result<Call<DeleteResponse>, ClientError> {
+  Call::new(CommandV0::Delete, &PlainDeleteQuery { key: key.to_string() }, true, decode)
+}
+
+pub(super) fn delete_query(query: DeleteQuery) -> Result<Call<DeleteResponse>, ClientError> {
+  let idempotent = query.if_version.is_none();
+  Call::new(CommandV0::DeleteWithOptions, &query, idempotent, decode)
+}
+
+pub(super) fn cas(query: CasQuery) -> Result<Call<bool>, ClientError> {
+  Call::new(CommandV0::CompareAndSwap, &query, false, |body| {
+    decode::<CasResponse>(body).map(|response| response.swapped)
+  })
+}
+
+pub(super) fn scan(query: ScanQuery) -> Result<Call<ScanResponse>, ClientError> {
+  Call::new(CommandV0::Scan, &query, true, decode)
+}
+
+/// Decides whether a failed attempt is tried again, with a backoff that doubles every time.
+pub(super) struct Retries {
+  left: u32,
+  backoff: Duration,
+}
+
+impl Retries {
+  pub fn new<R>(call: &Call<R>, retries: u32, backoff: Duration) -> Self {
+    Retries { left: if call.idempotent { retries } else { 0 }, backoff }
+  }
+
+  /// How long to wait before the next attempt, or [None] if `err` is final. Only connection
+  /// errors are retried, anything else came from the server.
+  pub fn next(&mut self, err: &ClientError) -> Option<Duration> {
+    if self.left == 0 || !err.is_connection_error() {
+      return None;
+    }
+    self.left -= 1;
+    let backoff = self.backoff;
+    self.backoff *= 2;
+    Some(backoff)
+  }
+}
@@ -0,0 +1,66 @@
+use std::{fmt, io};
+
+use crate::tcp::protocol::{ParsingResponseError, ResponseType};
+
+#[derive(Debug)]
+pub enum ClientError {
+  /// Connecting, sending or receiving failed.
+  Io(io::Error),
+  /// The server didn't respond within the configured timeout.
+  Timeout,
+  /// The `if_version` condition of a write didn't hold, so nothing was written.
+  ConditionFailed,
+  /// The server responded with an error.
+  Server { r#type: ResponseType, message: String },
+  /// The response could not be read or decoded. The connection is dropped afterwards.
+  InvalidResponse(String),
+}
+
+impl ClientError {
+  /// Errors after which the request may not have reached the server, or its response was lost.
+  pub fn is_connection_error(&self) -> bool {
+    matches!(self, ClientError::Io(_) | ClientError::Timeout)
+  }
+}
+
+impl fmt::Display for ClientError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ClientError::Io(err) => write!(f, "io error: {err}"),
+      ClientError::Timeout => write!(f, "request timed out"),
+      ClientError::ConditionFailed => write!(f, "condition failed"),
+      ClientError::Server { r#type, message } => write!(f, "server error {type:?}: {message}"),
+      ClientError::InvalidResponse(message) => write!(f, "invalid response: {message}"),
+    }
+  }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+  /// Timeouts of blocking streams surface as `WouldBlock` or `TimedOut`.
+  fn from(value: io::Error) -> Self {
+    match value.kind() {
+      io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ClientError::Timeout,
+      _ => ClientError::Io(value),
+    }
+  }
+}
+
+impl From<ParsingResponseError> for ClientError {
+  fn from(value: ParsingResponseError) -> Self {
+    match value {
+      ParsingResponseError::ConnectionClosed => {
+        ClientError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"))
+      }
+      ParsingResponseError::TimedOut => ClientError::Timeout,
+      _ => ClientError::InvalidResponse(format!("{value:?}")),
+    }
+  }
+}
+
+impl From<bincode::Error> for ClientError {
+  fn from(value: bincode::Error) -> Self {
+    ClientError::InvalidResponse(value.to_string())
+  }
+}
@@ -0,0 +1,405 @@
+pub mod blocking;
+mod call;
+mod error;
+mod pool;
+
+pub use error::ClientError;
+
+use std::{
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use tokio::time::{sleep, timeout};
+
+use crate::{
+  public_api::{
+    auth::AuthQuery,
+    dataquery::{CasQuery, DeleteQuery, DeleteResponse, PutQuery, ReadResponse},
+    ping::PingResponse,
+    scan::{ScanQuery, ScanResponse},
+  },
+  tcp::{
+    protocol::{ErrorBody, RawRequest, RawResponse, ResponseType},
+    server::CommandV0,
+    tls::ClientTls,
+  },
+};
+use call::{Call, Retries};
+use pool::{Endpoint, Pool};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Wait before the first retry. It doubles with every further retry.
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Async client for a memory-db server. Connections are pooled, so the client is cheap to clone
+/// and share between tasks.
+///
+/// Requests that fail because of the connection are retried, but only if running them twice
+/// does no harm. Conditional writes and compare-and-swaps are never retried.
+#[derive(Clone)]
+pub struct Client {
+  pool: Arc<Pool>,
+  endpoint: Endpoint,
+  max_connections: usize,
+  credentials: Option<AuthQuery>,
+  next_id: Arc<AtomicU32>,
+  timeout: Duration,
+  retries: u32,
+  retry_backoff: Duration,
+}
+
+impl Client {
+  /// Doesn't connect yet, connections are opened on demand.
+  pub fn new(address: &str) -> Self {
+    Client::with_endpoint(Endpoint::Tcp(address.to_string()))
+  }
+
+  /// Client for a [crate::tcp::server::TcpServer] with TLS, see
+  /// [crate::tcp::tls::ClientTlsConfig::load].
+  pub fn new_tls(address: &str, tls: ClientTls) -> Self {
+    Client::with_endpoint(Endpoint::Tls(address.to_string(), tls))
+  }
+
+  /// Client for a [crate::tcp::unix_server::UnixServer] listening on `path`.
+  #[cfg(unix)]
+  pub fn new_unix(path: impl Into<std::path::PathBuf>) -> Self {
+    Client::with_endpoint(Endpoint::Unix(path.into()))
+  }
+
+  fn with_endpoint(endpoint: Endpoint) -> Self {
+    Client {
+      pool: Arc::new(Pool::new(endpoint.clone(), DEFAULT_MAX_CONNECTIONS, None)),
+      endpoint,
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      credentials: None,
+      next_id: Arc::new(AtomicU32::new(0)),
+      timeout: DEFAULT_TIMEOUT,
+      retries: DEFAULT_RETRIES,
+      retry_backoff: DEFAULT_RETRY_BACKOFF,
+    }
+  }
+
+  /// Limits how many connections are open at the same time. Further requests wait for a free
+  /// connection.
+  pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+    self.max_connections = max_connections;
+    self.with_new_pool()
+  }
+
+  /// Authenticates every connection as `user`, for servers with an ACL.
+  pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+    self.credentials = Some(AuthQuery { user: user.to_string(), password: password.to_string() });
+    self.with_new_pool()
+  }
+
+  fn with_new_pool(mut self) -> Self {
+    self.pool =
+      Arc::new(Pool::new(self.endpoint.clone(), self.max_connections, self.credentials.clone()));
+    self
+  }
+
+  /// Time a single attempt of a request may take, including waiting for a connection.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+    self.retries = retries;
+    self.retry_backoff = backoff;
+    self
+  }
+
+  pub async fn ping(&self) -> Result<PingResponse, ClientError> {
+    self.call(call::ping()?).await
+  }
+
+  /// [None] if the key doesn't exist.
+  pub async fn get(&self, key: &str) -> Result<Option<ReadResponse>, ClientError> {
+    self.call(call::get(key)?).await
+  }
+
+  /// Returns the version of the written value.
+  pub async fn put(&self, key: &str, value: Vec<u8>) -> Result<u64, ClientError> {
+    self.call(call::put(key, value)?).await
+  }
+
+  /// Put with an expiry or `if_version` condition. Returns the version of the written value.
+  pub async fn put_query(&self, query: PutQuery) -> Result<u64, ClientError> {
+    self.call(call::put_query(query)?).await
+  }
+
+  pub async fn delete(&self, key: &str) -> Result<DeleteResponse, ClientError> {
+    self.call(call::delete(key)?).await
+  }
+
+  pub async fn delete_query(&self, query: DeleteQuery) -> Result<DeleteResponse, ClientError> {
+    self.call(call::delete_query(query)?).await
+  }
+
+  /// Whether the value was swapped.
+  pub async fn cas(&self, query: CasQuery) -> Result<bool, ClientError> {
+    self.call(call::cas(query)?).await
+  }
+
+  pub async fn scan(&self, query: ScanQuery) -> Result<ScanResponse, ClientError> {
+    self.call(call::scan(query)?).await
+  }
+
+  async fn call<R>(&self, call: Call<R>) -> Result<R, ClientError> {
+    let mut retries = Retries::new(&call, self.retries, self.retry_backoff);
+    let result = loop {
+      match self.send(call.command, call.body.clone()).await {
+        Err(err) => match retries.next(&err) {
+          Some(backoff) => {
+            tracing::debug!("Retrying {:?} after: {}", call.command, err);
+            sleep(backoff).await;
+          }
+          None => break Err(err),
+        },
+        result => break result,
+      }
+    };
+
+    (call.response)(result)
+  }
+
+  /// Sends the request once and returns the body of a successful response.
+  async fn send(&self, command: CommandV0, body: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let request = RawRequest::new_v1(id, command.into(), body);
+
+    let mut pooled =
+      timeout(self.timeout, self.pool.get()).await.map_err(|_| ClientError::Timeout)??;
+    let exchange = async {
+      pooled.conn.write_request(&request).await?;
+      Ok::<_, ClientError>(pooled.conn.read_response().await?)
+    };
+
+    let response = match timeout(self.timeout, exchange).await {
+      Ok(Ok(response)) => response,
+      Ok(Err(err)) => {
+        // The other idle connections are probably broken as well, e.g. the server restarted.
+        if err.is_connection_error() {
+          self.pool.clear();
+        }
+        return Err(err);
+      }
+      Err(_) => return Err(ClientError::Timeout),
+    };
+
+    check_id(&response, id)?;
+    pooled.release();
+
+    response_body(response)
+  }
+}
+
+/// Sent first on every new connection of a client with credentials.
+fn auth_request(credentials: &AuthQuery) -> Result<RawRequest, ClientError> {
+  Ok(RawRequest::new_v1(0, CommandV0::Auth.into(), bincode::serialize(credentials)?))
+}
+
+/// Responses are matched to requests by order, the id only guards against mixups.
+fn check_id(response: &RawResponse, id: u32) -> Result<(), ClientError> {
+  if response.id != id {
+    return Err(ClientError::InvalidResponse(format!(
+      "Expected response to request {id}, got {}",
+      response.id
+    )));
+  }
+  Ok(())
+}
+
+/// The body of a successful response, or the error the response stands for.
+fn response_body(response: RawResponse) -> Result<Vec<u8>, ClientError> {
+  match ResponseType::try_from(response.r#type) {
+    Ok(ResponseType::Ok) => Ok(response.body),
+    Ok(ResponseType::ConditionFailed) => Err(ClientError::ConditionFailed),
+    Ok(r#type) => {
+      let message = bincode::deserialize::<ErrorBody>(&response.body)
+        .map(|body| body.message)
+        .unwrap_or_default();
+      Err(ClientError::Server { r#type, message })
+    }
+    Err(()) => {
+      Err(ClientError::InvalidResponse(format!("Unknown response type {}", response.r#type)))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{atomic::AtomicUsize, Mutex};
+
+  use serde::Serialize;
+  use tokio::{net::TcpListener, task};
+
+  use super::*;
+  use crate::{public_api::dataquery::CasExpectation, tcp::protocol::Connection};
+
+  /// Answers every request with `respond`, which gets the request and how many came before it.
+  /// [None] closes the connection instead of answering.
+  struct FakeServer {
+    address: String,
+    connections: Arc<AtomicUsize>,
+    /// Every request received.
+    requests: Arc<Mutex<Vec<RawRequest>>>,
+  }
+
+  impl FakeServer {
+    async fn start(respond: fn(&RawRequest, usize) -> Option<RawResponse>) -> Self {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap().to_string();
+      let connections = Arc::new(AtomicUsize::new(0));
+      let requests = Arc::new(Mutex::new(Vec::new()));
+
+      let (accepted, received) = (connections.clone(), requests.clone());
+      tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+          accepted.fetch_add(1, Ordering::SeqCst);
+          let received = received.clone();
+          tokio::spawn(async move {
+            let mut conn = Connection::new(stream);
+            while let Ok(request) = conn.read_request().await {
+              let count = {
+                let mut received = received.lock().unwrap();
+                received.push(RawRequest::new_v1(
+                  request.id,
+                  request.command,
+                  request.body.clone(),
+                ));
+                received.len() - 1
+              };
+              let Some(response) = respond(&request, count) else { return };
+              let response = response.for_request(request.version, request.id);
+              conn.write_response(&response).await.unwrap();
+            }
+          });
+        }
+      });
+
+      FakeServer { address, connections, requests }
+    }
+
+    fn client(&self) -> Client {
+      Client::new(&self.address).with_retries(2, Duration::from_millis(1))
+    }
+
+    fn connections(&self) -> usize {
+      self.connections.load(Ordering::SeqCst)
+    }
+
+    fn commands(&self) -> Vec<u8> {
+      self.requests.lock().unwrap().iter().map(|request| request.command).collect()
+    }
+  }
+
+  fn ok<T: Serialize>(body: &T) -> Option<RawResponse> {
+    Some(RawResponse::new(ResponseType::Ok.into(), bincode::serialize(body).unwrap()))
+  }
+
+  fn pong() -> Option<RawResponse> {
+    let ping = PingResponse { version: String::new(), node_id: 1, uptime_ms: 0, raft_role: None };
+    ok(&ping)
+  }
+
+  #[tokio::test]
+  async fn reuses_pooled_connections() {
+    let server = FakeServer::start(|_, _| pong()).await;
+    let client = server.client();
+    for _ in 0..3 {
+      client.ping().await.unwrap();
+    }
+    assert_eq!(server.connections(), 1);
+  }
+
+  #[tokio::test]
+  async fn maps_not_found_to_none() {
+    let server =
+      FakeServer::start(|_, _| Some(RawResponse::error(ResponseType::NotFound, "missing"))).await;
+    assert!(server.client().get("k").await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn retries_only_connection_errors() {
+    // The first connection is closed without an answer.
+    let server = FakeServer::start(|_, count| if count == 0 { None } else { pong() }).await;
+    server.client().ping().await.unwrap();
+    assert_eq!(server.commands().len(), 2);
+    assert_eq!(server.connections(), 2);
+
+    let server =
+      FakeServer::start(|_, _| Some(RawResponse::error(ResponseType::InternalError, "failed")))
+        .await;
+    let err = server.client().ping().await.unwrap_err();
+    assert!(matches!(err, ClientError::Server { r#type: ResponseType::InternalError, .. }));
+    assert_eq!(server.commands().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn never_retries_conditional_writes() {
+    let server = FakeServer::start(|_, _| None).await;
+    let client = server.client();
+
+    let cas = CasQuery {
+      key: "k".to_string(),
+      expected: CasExpectation::Missing,
+      value: vec![],
+      expiry: None,
+    };
+    assert!(client.cas(cas).await.unwrap_err().is_connection_error());
+    let put = PutQuery { key: "k".to_string(), value: vec![], expiry: None, if_version: Some(1) };
+    assert!(client.put_query(put.clone()).await.unwrap_err().is_connection_error());
+    let delete = DeleteQuery { key: "k".to_string(), if_version: Some(1) };
+    assert!(client.delete_query(delete).await.unwrap_err().is_connection_error());
+    assert_eq!(server.commands().len(), 3);
+
+    // The same put without a condition is retried.
+    client.put_query(PutQuery { if_version: None, ..put }).await.unwrap_err();
+    assert_eq!(server.commands().len(), 6);
+  }
+
+  #[tokio::test]
+  async fn authenticates_new_connections() {
+    let server = FakeServer::start(|request, _| match CommandV0::try_from(request.command) {
+      Ok(CommandV0::Auth) => ok(&()),
+      _ => pong(),
+    })
+    .await;
+    let client = server.client().with_credentials("user", "password");
+    client.ping().await.unwrap();
+    client.ping().await.unwrap();
+
+    let auth = u8::from(CommandV0::Auth);
+    let ping = u8::from(CommandV0::Ping);
+    assert_eq!(server.commands(), [auth, ping, ping]);
+    let credentials: AuthQuery =
+      bincode::deserialize(&server.requests.lock().unwrap()[0].body).unwrap();
+    assert_eq!((credentials.user.as_str(), credentials.password.as_str()), ("user", "password"));
+  }
+
+  #[tokio::test]
+  async fn blocking_client_behaves_the_same() {
+    let server = FakeServer::start(|request, _| match CommandV0::try_from(request.command) {
+      Ok(CommandV0::Get) => Some(RawResponse::error(ResponseType::NotFound, "missing")),
+      _ => pong(),
+    })
+    .await;
+    let address = server.address.clone();
+    task::spawn_blocking(move || {
+      let client = blocking::Client::new(&address);
+      client.ping().unwrap();
+      assert!(client.get("k").unwrap().is_none());
+    })
+    .await
+    .unwrap();
+    assert_eq!(server.connections(), 1);
+  }
+}
@@ -0,0 +1,110 @@
+use std::{
+  path::PathBuf,
+  sync::{Mutex, PoisonError},
+};
+
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::TcpStream,
+  sync::{Semaphore, SemaphorePermit},
+};
+
+use tokio_rustls::TlsConnector;
+
+use super::{auth_request, response_body, ClientError};
+use crate::{
+  public_api::auth::AuthQuery,
+  tcp::{protocol::Connection, tls::ClientTls},
+};
+
+/// Where the server listens.
+#[derive(Clone, Debug)]
+pub(crate) enum Endpoint {
+  Tcp(String),
+  Tls(String, ClientTls),
+  #[cfg(unix)]
+  Unix(PathBuf),
+}
+
+/// Any stream a client connection can run over.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Keeps idle connections around, so requests don't pay for a new connection every time.
+pub(crate) struct Pool {
+  endpoint: Endpoint,
+  idle: Mutex<Vec<Connection<Box<dyn Stream>>>>,
+  /// One permit per open connection, idle or in use.
+  permits: Semaphore,
+  credentials: Option<AuthQuery>,
+}
+
+/// A connection taken from the [Pool]. It is closed when dropped, unless it is handed back with
+/// [PooledConnection::release].
+pub(crate) struct PooledConnection<'a> {
+  pub conn: Connection<Box<dyn Stream>>,
+  pool: &'a Pool,
+  _permit: SemaphorePermit<'a>,
+}
+
+impl Pool {
+  pub fn new(endpoint: Endpoint, max_connections: usize, credentials: Option<AuthQuery>) -> Self {
+    Pool {
+      endpoint,
+      idle: Mutex::new(Vec::new()),
+      permits: Semaphore::new(max_connections),
+      credentials,
+    }
+  }
+
+  /// An idle connection, or a new one. Waits while `max_connections` are in use.
+  pub async fn get(&self) -> Result<PooledConnection<'_>, ClientError> {
+    let permit = self.permits.acquire().await.expect("The semaphore is never closed");
+
+    let idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+    let conn = match idle {
+      Some(conn) => conn,
+      None => {
+        let mut conn = Connection::new(self.connect().await?);
+        if let Some(credentials) = &self.credentials {
+          conn.write_request(&auth_request(credentials)?).await?;
+          response_body(conn.read_response().await?)?;
+        }
+        conn
+      }
+    };
+
+    Ok(PooledConnection { conn, pool: self, _permit: permit })
+  }
+
+  async fn connect(&self) -> std::io::Result<Box<dyn Stream>> {
+    match &self.endpoint {
+      Endpoint::Tcp(address) => {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+      }
+      Endpoint::Tls(address, tls) => {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        let connector = TlsConnector::from(tls.config.clone());
+        Ok(Box::new(connector.connect(tls.server_name.clone(), stream).await?))
+      }
+      #[cfg(unix)]
+      Endpoint::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+    }
+  }
+
+  /// Drops all idle connections, e.g. after the server restarted.
+  pub fn clear(&self) {
+    self.idle.lock().unwrap_or_else(PoisonError::into_inner).clear();
+  }
+}
+
+impl PooledConnection<'_> {
+  /// Puts the connection back into the pool. Only do this after a complete request and
+  /// response, so the next user doesn't read a stale response.
+  pub fn release(self) {
+    self.pool.idle.lock().unwrap_or_else(PoisonError::into_inner).push(self.conn);
+  }
+}
@@ -0,0 +1,263 @@
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::tcp::{server::DEFAULT_MAX_CONNECTIONS, tls::ServerTlsConfig};
+
+/// Settings of the server. Read from a TOML file like:
+///
+/// ```toml
+/// listen = "0.0.0.0:8000"
+/// node_id = 1
+/// unix_socket = "/run/memorydb.sock"
+/// unix_socket_mode = 0o660
+/// acl_file = "/etc/memorydb/acl.toml"
+/// log_level = "info"
+///
+/// [tls]
+/// cert_path = "/etc/memorydb/server.pem"
+/// key_path = "/etc/memorydb/server.key"
+///
+/// [storage]
+/// data_dir = "/var/lib/memorydb"
+/// snapshot_interval_sec = 60
+/// snapshot_keep = 10
+/// wal_fsync = "interval"
+/// wal_fsync_interval_ms = 100
+/// ```
+///
+/// Missing settings keep their defaults. `MEMORY_DB_*` environment variables override the file,
+/// and command line flags override both, see [Args].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// TCP address to listen on.
+  pub listen: String,
+  /// Id of this node in the cluster, reported by Ping. Without one, a random id is picked on the
+  /// first start and kept in [StorageConfig::data_dir].
+  pub node_id: Option<u64>,
+  /// Also listen on this Unix domain socket.
+  pub unix_socket: Option<PathBuf>,
+  /// Mode of the [Config::unix_socket] file, see
+  /// [crate::tcp::unix_server::UnixServer::with_permissions].
+  pub unix_socket_mode: Option<u32>,
+  /// Per listener, see [crate::tcp::server::TcpServer::with_max_connections].
+  pub max_connections: usize,
+  /// Only accept TLS connections on [Config::listen].
+  pub tls: Option<ServerTlsConfig>,
+  /// See [crate::state::Acl]. Without one, every client may run every query.
+  pub acl_file: Option<PathBuf>,
+  /// One of trace, debug, info, warn or error.
+  pub log_level: String,
+  pub storage: StorageConfig,
+}
+
+/// Where and how [crate::state::State] persists its data.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+  /// Holds the WAL segments and the snapshots.
+  pub data_dir: PathBuf,
+  pub snapshot_interval_sec: u64,
+  /// Number of snapshots kept, older ones are deleted.
+  pub snapshot_keep: usize,
+  pub wal_fsync: WalFsync,
+  /// How often the WAL is flushed with [WalFsync::Interval].
+  pub wal_fsync_interval_ms: u64,
+}
+
+/// When appends to the WAL are flushed to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WalFsync {
+  /// Before a write is acknowledged.
+  Always,
+  /// Every [StorageConfig::wal_fsync_interval_ms]. Writes acknowledged since the last flush can
+  /// be lost on power failure.
+  Interval,
+  /// Whenever the OS decides to. Acknowledged writes can be lost on power failure.
+  #[default]
+  Os,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      listen: "127.0.0.1:8000".to_string(),
+      node_id: None,
+      unix_socket: None,
+      unix_socket_mode: None,
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      tls: None,
+      acl_file: None,
+      log_level: "trace".to_string(),
+      storage: StorageConfig::default(),
+    }
+  }
+}
+
+impl Default for StorageConfig {
+  fn default() -> Self {
+    StorageConfig {
+      #[cfg(debug_assertions)]
+      data_dir: PathBuf::from("./memorydb"),
+      #[cfg(not(debug_assertions))]
+      data_dir: PathBuf::from("/etc/memorydb"),
+      #[cfg(debug_assertions)]
+      snapshot_interval_sec: 5,
+      #[cfg(not(debug_assertions))]
+      snapshot_interval_sec: 60,
+      snapshot_keep: 10,
+      wal_fsync: WalFsync::default(),
+      wal_fsync_interval_ms: 100,
+    }
+  }
+}
+
+impl StorageConfig {
+  pub fn snapshot_dir(&self) -> PathBuf {
+    self.data_dir.join("snapshots")
+  }
+
+  pub fn wal_dir(&self) -> PathBuf {
+    self.data_dir.join("wal")
+  }
+}
+
+/// Command line flags. Each one can also be set with the environment variable next to it.
+#[derive(Debug, Parser)]
+#[command(version, about = "Runs a memory-db server")]
+pub struct Args {
+  /// TOML file to read the settings from.
+  #[arg(short, long, env = "MEMORY_DB_CONFIG")]
+  pub config: Option<PathBuf>,
+  #[arg(long, env = "MEMORY_DB_LISTEN")]
+  pub listen: Option<String>,
+  #[arg(long, env = "MEMORY_DB_NODE_ID")]
+  pub node_id: Option<u64>,
+  #[arg(long, env = "MEMORY_DB_UNIX_SOCKET")]
+  pub unix_socket: Option<PathBuf>,
+  /// Octal, like 660.
+  #[arg(long, env = "MEMORY_DB_UNIX_SOCKET_MODE", value_parser = parse_mode)]
+  pub unix_socket_mode: Option<u32>,
+  #[arg(long, env = "MEMORY_DB_MAX_CONNECTIONS")]
+  pub max_connections: Option<usize>,
+  /// PEM certificate chain. Enables TLS together with --tls-key.
+  #[arg(long, env = "MEMORY_DB_TLS_CERT", requires = "tls_key")]
+  pub tls_cert: Option<PathBuf>,
+  #[arg(long, env = "MEMORY_DB_TLS_KEY", requires = "tls_cert")]
+  pub tls_key: Option<PathBuf>,
+  /// Require client certificates signed by these CAs.
+  #[arg(long, env = "MEMORY_DB_TLS_CLIENT_CA", requires = "tls_cert")]
+  pub tls_client_ca: Option<PathBuf>,
+  #[arg(long, env = "MEMORY_DB_ACL_FILE")]
+  pub acl_file: Option<PathBuf>,
+  #[arg(long, env = "MEMORY_DB_LOG_LEVEL")]
+  pub log_level: Option<String>,
+  #[arg(long, env = "MEMORY_DB_DATA_DIR")]
+  pub data_dir: Option<PathBuf>,
+  #[arg(long, env = "MEMORY_DB_SNAPSHOT_INTERVAL_SEC")]
+  pub snapshot_interval_sec: Option<u64>,
+  #[arg(long, env = "MEMORY_DB_SNAPSHOT_KEEP")]
+  pub snapshot_keep: Option<usize>,
+  #[arg(long, env = "MEMORY_DB_WAL_FSYNC")]
+  pub wal_fsync: Option<WalFsync>,
+  #[arg(long, env = "MEMORY_DB_WAL_FSYNC_INTERVAL_MS")]
+  pub wal_fsync_interval_ms: Option<u64>,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+  let mode = mode.strip_prefix("0o").unwrap_or(mode);
+  u32::from_str_radix(mode, 8).map_err(|err| format!("Not an octal mode: {err}"))
+}
+
+impl Config {
+  pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+    toml::from_str(&fs::read_to_string(path)?)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+  }
+
+  /// The config file named in `args`, or the defaults, with `args` applied on top.
+  pub fn load(args: Args) -> io::Result<Self> {
+    let mut config = match &args.config {
+      Some(path) => Config::from_file(path)?,
+      None => Config::default(),
+    };
+
+    if let Some(listen) = args.listen {
+      config.listen = listen;
+    }
+    if let Some(node_id) = args.node_id {
+      config.node_id = Some(node_id);
+    }
+    if let Some(unix_socket) = args.unix_socket {
+      config.unix_socket = Some(unix_socket);
+    }
+    if let Some(unix_socket_mode) = args.unix_socket_mode {
+      config.unix_socket_mode = Some(unix_socket_mode);
+    }
+    if let Some(max_connections) = args.max_connections {
+      config.max_connections = max_connections;
+    }
+    if let (Some(cert_path), Some(key_path)) = (args.tls_cert, args.tls_key) {
+      config.tls =
+        Some(ServerTlsConfig { cert_path, key_path, client_ca_path: args.tls_client_ca });
+    }
+    if let Some(acl_file) = args.acl_file {
+      config.acl_file = Some(acl_file);
+    }
+    if let Some(log_level) = args.log_level {
+      config.log_level = log_level;
+    }
+    if let Some(data_dir) = args.data_dir {
+      config.storage.data_dir = data_dir;
+    }
+    if let Some(snapshot_interval_sec) = args.snapshot_interval_sec {
+      config.storage.snapshot_interval_sec = snapshot_interval_sec;
+    }
+    if let Some(snapshot_keep) = args.snapshot_keep {
+      config.storage.snapshot_keep = snapshot_keep;
+    }
+    if let Some(wal_fsync) = args.wal_fsync {
+      config.storage.wal_fsync = wal_fsync;
+    }
+    if let Some(wal_fsync_interval_ms) = args.wal_fsync_interval_ms {
+      config.storage.wal_fsync_interval_ms = wal_fsync_interval_ms;
+    }
+
+    config.validate()?;
+    Ok(config)
+  }
+
+  fn validate(&self) -> io::Result<()> {
+    if self.node_id == Some(0) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "node_id must not be 0"));
+    }
+    if self.storage.snapshot_interval_sec == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "snapshot_interval_sec must not be 0",
+      ));
+    }
+    if self.storage.snapshot_keep == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "snapshot_keep must be at least 1"));
+    }
+    if self.max_connections == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "max_connections must not be 0"));
+    }
+    if self.storage.wal_fsync_interval_ms == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "wal_fsync_interval_ms must not be 0",
+      ));
+    }
+    if self.unix_socket_mode.is_some_and(|mode| mode > 0o777) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "unix_socket_mode must be <= 0o777"));
+    }
+    Ok(())
+  }
+}
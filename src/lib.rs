@@ -1,4 +1,6 @@
 pub mod app;
+pub mod client;
+pub mod config;
 pub mod log;
 pub mod prelude;
 pub mod public_api;
@@ -1,42 +1,147 @@
-use chrono::Utc;
+pub mod wal;
+
 use serde::{Deserialize, Serialize};
 
-use crate::public_api::dataquery::{DataQuery, DeleteQuery, PutQuery};
-
-impl DataQuery {
-  pub fn as_datachangelogs(&self) -> Option<Vec<DataChangeLog>> {
-    let date = Utc::now().timestamp();
-    match self {
-      DataQuery::Read(_) => None,
-      DataQuery::Put(put_query) => Some(Vec::from_iter([DataChangeLog {
-        query: DataChangeQuery::Put(put_query.clone()),
-        date,
-      }])),
-      DataQuery::Delete(delete_query) => Some(Vec::from_iter([DataChangeLog {
-        query: DataChangeQuery::Delete(delete_query.clone()),
-        date,
-      }])),
-    }
-  }
+use crate::{
+  prelude::{DataStore, DataStoreKey, DataStoreValue},
+  public_api::{
+    dataquery::{DeleteQuery, PutQuery},
+    transaction::{TransactionOp, TransactionQuery},
+  },
+};
+
+/// What executing queries changed in the store, as logs for the WAL. Queries record each change
+/// right when they make it, so the logs hold exactly what was done, with the versions it got.
+/// The values the changes replaced are kept as well, to undo them if the WAL write fails.
+#[derive(Default)]
+pub struct Changes {
+  logs: Vec<DataChangeLog>,
+  /// Each written key with the value it had before, oldest first.
+  replaced: Vec<(DataStoreKey, Option<DataStoreValue>)>,
 }
 
-impl From<DataChangeQuery> for DataQuery {
-  fn from(value: DataChangeQuery) -> Self {
-    match value {
-      DataChangeQuery::Delete(query) => DataQuery::Delete(query),
-      DataChangeQuery::Put(query) => DataQuery::Put(query),
+impl Changes {
+  /// Records a change that brought the store to `version`. Conditions have to be dropped from
+  /// `query` already, it is replayed as it is.
+  pub(crate) fn log(&mut self, query: DataChangeQuery, version: u64, now: i64) {
+    self.logs.push(DataChangeLog { query, version, date: now / 1000 });
+  }
+
+  /// Records the value `key` had before a write, including expired ones. Call it for every
+  /// write, in the order of the writes.
+  pub(crate) fn replaced(&mut self, key: DataStoreKey, previous: Option<DataStoreValue>) {
+    self.replaced.push((key, previous));
+  }
+
+  /// Puts back the values the changes replaced, newest first.
+  pub(crate) fn undo(self, store: &DataStore) {
+    for (key, previous) in self.replaced.into_iter().rev() {
+      match previous {
+        Some(value) => store.insert(key, value),
+        None => {
+          store.remove(&key);
+        }
+      }
     }
   }
+
+  pub fn is_empty(&self) -> bool {
+    self.logs.is_empty()
+  }
+
+  pub fn into_logs(self) -> Vec<DataChangeLog> {
+    self.logs
+  }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct DataChangeLog {
   pub query: DataChangeQuery,
+  /// Version the store is at after the change.
+  pub version: u64,
   date: i64,
 }
 
+impl DataChangeLog {
+  /// Makes the change again, with the version it had. Expiries in logs are absolute, so `now`
+  /// doesn't change the outcome.
+  pub fn replay(self, store: &DataStore, now: i64) {
+    let version = self.version;
+    match self.query {
+      DataChangeQuery::Put(query) => query.apply(store, version, now),
+      DataChangeQuery::Delete(query) => query.apply(store),
+      DataChangeQuery::Transaction(transaction) => {
+        for op in transaction.operations {
+          match op {
+            TransactionOp::Put(query) => query.apply(store, version, now),
+            TransactionOp::Delete(query) => query.apply(store),
+            TransactionOp::Read(_) | TransactionOp::Check { .. } => {}
+          }
+        }
+      }
+    }
+    store.advance_version(version);
+  }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DataChangeQuery {
   Put(PutQuery),
   Delete(DeleteQuery),
+  Transaction(TransactionQuery),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::public_api::dataquery::{DataQuery, Expiry, HandleQuery as _, QueryError};
+
+  fn put(key: &str, if_version: Option<u64>) -> PutQuery {
+    PutQuery { key: key.to_string(), value: b"v".to_vec(), expiry: None, if_version }
+  }
+
+  #[test]
+  fn logs_what_was_executed() {
+    let store = DataStore::default();
+    let now = 1_000;
+    let mut changes = Changes::default();
+
+    let ttl = PutQuery { expiry: Some(Expiry::Ttl(10)), ..put("a", None) };
+    DataQuery::Put(ttl).exec(store.clone(), now, &mut changes).unwrap();
+    // Expired by the time the condition is checked, so the key counts as missing.
+    put("a", Some(0)).exec(store.clone(), now + 10, &mut changes).unwrap();
+    let failed = put("a", Some(1)).exec(store.clone(), now + 10, &mut changes);
+    assert!(matches!(failed, Err(QueryError::ConditionFailed)));
+
+    let logs = changes.into_logs();
+    assert_eq!(logs.iter().map(|log| log.version).collect::<Vec<_>>(), [1, 2]);
+    match &logs[0].query {
+      DataChangeQuery::Put(put) => assert!(matches!(put.expiry, Some(Expiry::At(1_010)))),
+      query => panic!("Unexpected log {query:?}"),
+    }
+    assert!(matches!(&logs[1].query, DataChangeQuery::Put(put) if put.if_version.is_none()));
+
+    let replayed = DataStore::default();
+    for log in logs {
+      log.replay(&replayed, now);
+    }
+    assert_eq!(replayed.version(), store.version());
+    assert_eq!(replayed.live_version(&"a".into(), now + 10), 2);
+  }
+
+  #[test]
+  fn undoes_changes() {
+    let store = DataStore::default();
+    put("a", None).exec(store.clone(), 0, &mut Changes::default()).unwrap();
+
+    let mut changes = Changes::default();
+    put("a", None).exec(store.clone(), 0, &mut changes).unwrap();
+    put("b", None).exec(store.clone(), 0, &mut changes).unwrap();
+    let delete = DeleteQuery { key: "a".to_string(), if_version: None };
+    DataQuery::Delete(delete).exec(store.clone(), 0, &mut changes).unwrap();
+    changes.undo(&store);
+
+    assert_eq!(store.live_version(&"a".into(), 0), 1);
+    assert!(store.get_live(&"b".into(), 0).is_none());
+  }
 }
@@ -0,0 +1,612 @@
+//! The write-ahead log. Each segment file starts with [MAGIC] and [FORMAT_VERSION], followed by
+//! records of `[u32 length][u32 crc32c][length bytes]`, little endian. The bytes of a record are
+//! the bincode encoded logs of one query, so a query is replayed completely or not at all.
+
+use std::{
+  fs::{self, File, OpenOptions},
+  future::Future,
+  io::{self, Read as _, Write as _},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, PoisonError, RwLock,
+  },
+  thread,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{Changes, DataChangeLog, DataChangeQuery};
+use crate::{
+  prelude::{DataStore, UNVERSIONED_VERSION},
+  public_api::dataquery::{PlainDeleteQuery, PlainPutQuery},
+  state::utils,
+};
+
+pub const MAGIC: [u8; 4] = *b"MDBW";
+/// Bumped whenever the layout changes, so older files can be told apart.
+pub const FORMAT_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + 1;
+const RECORD_HEADER_LEN: usize = 8;
+
+/// Upper bound of records the [WalWriter] writes at once.
+const MAX_BATCH: usize = 1024;
+/// Requests that can wait for the [WalWriter], further writers wait for room.
+const QUEUE_LEN: usize = MAX_BATCH;
+
+/// The WAL, split into numbered segment files in one directory. Appends go to the newest
+/// segment, whose file is kept open between appends. A new segment is started with
+/// [Wal::rotate] when a snapshot is taken, so older segments can be deleted once the snapshot
+/// is on disk.
+pub struct Wal {
+  dir: PathBuf,
+  /// Segment appends go to. On first use, the newest one in `dir`.
+  segment: Option<u64>,
+  file: Option<File>,
+  len: u64,
+  /// Appended to since the last [Wal::sync].
+  dirty: bool,
+}
+
+impl Wal {
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Wal { dir: dir.into(), segment: None, file: None, len: 0, dirty: false }
+  }
+
+  /// Appends every entry of `records` as one record, all with a single write. They are only
+  /// durable after [Wal::sync].
+  pub fn append(&mut self, records: &[Vec<DataChangeLog>]) -> io::Result<()> {
+    if self.file.is_none() {
+      let segment = self.segment()?;
+      self.open(segment)?;
+    }
+    let file = self.file.as_mut().expect("Opened above");
+
+    let mut buf = Vec::new();
+    if self.len == 0 {
+      buf.extend(header());
+    }
+    for logs in records {
+      encode_record(&mut buf, logs)?;
+    }
+
+    if let Err(err) = file.write_all(&buf) {
+      // Don't leave half a record in front of the next one.
+      let _ = file.set_len(self.len);
+      return Err(err);
+    }
+    self.len += buf.len() as u64;
+    self.dirty = true;
+    Ok(())
+  }
+
+  /// Flushes the appended records to disk.
+  pub fn sync(&mut self) -> io::Result<()> {
+    if let (Some(file), true) = (&self.file, self.dirty) {
+      file.sync_data()?;
+      self.dirty = false;
+    }
+    Ok(())
+  }
+
+  /// Finishes the current segment and starts the next one. Returns the number of the new segment.
+  pub fn rotate(&mut self) -> io::Result<u64> {
+    self.sync()?;
+    let next = self.segment()? + 1;
+    // Created right away, so it's found as the newest segment even if nothing is appended to it.
+    self.open(next)?;
+    Ok(next)
+  }
+
+  /// Deletes the segments older than `segment`.
+  pub fn remove_segments_before(&self, segment: u64) -> io::Result<()> {
+    for old in segments(&self.dir)?.into_iter().take_while(|old| *old < segment) {
+      fs::remove_file(segment_path(&self.dir, old))?;
+    }
+    Ok(())
+  }
+
+  fn segment(&mut self) -> io::Result<u64> {
+    if let Some(segment) = self.segment {
+      return Ok(segment);
+    }
+    let segment = segments(&self.dir)?.last().copied().unwrap_or_default();
+    self.segment = Some(segment);
+    Ok(segment)
+  }
+
+  fn open(&mut self, segment: u64) -> io::Result<()> {
+    fs::create_dir_all(&self.dir)?;
+    let file =
+      OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, segment))?;
+    self.len = file.metadata()?.len();
+    self.file = Some(file);
+    self.segment = Some(segment);
+    self.dirty = false;
+    Ok(())
+  }
+}
+
+impl Drop for Wal {
+  fn drop(&mut self) {
+    if let Err(err) = self.sync() {
+      tracing::error!("WAL sync error: {:?}", err);
+    }
+  }
+}
+
+/// Path of the segment with number `segment` in `dir`.
+pub fn segment_path(dir: impl AsRef<Path>, segment: u64) -> PathBuf {
+  dir.as_ref().join(format!("{segment:020}.wal"))
+}
+
+/// Numbers of the segments in `dir`, oldest first.
+pub fn segments(dir: impl AsRef<Path>) -> io::Result<Vec<u64>> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(err) => return Err(err),
+  };
+
+  let mut segments = Vec::new();
+  for entry in entries {
+    let name = entry?.file_name();
+    let segment = name.to_str().and_then(|name| name.strip_suffix(".wal"));
+    segments.extend(segment.and_then(|segment| segment.parse::<u64>().ok()));
+  }
+  segments.sort_unstable();
+  Ok(segments)
+}
+
+/// Commits the logs of concurrent writers as a group: everything queued while the previous batch
+/// was written goes into the next batch, with a single write and at most one flush.
+///
+/// The changes are already in the store when they are queued. If a batch can't be written, they
+/// and everything queued after them are undone, and the writer refuses every further write, since
+/// the store must not get ahead of the WAL.
+#[derive(Clone)]
+pub struct WalWriter {
+  queue: mpsc::Sender<Request>,
+  failed: Arc<AtomicBool>,
+}
+
+/// A slot in the queue of the [WalWriter], see [WalWriter::reserve].
+pub struct WalPermit(mpsc::OwnedPermit<Request>);
+
+enum Request {
+  Append(Changes, oneshot::Sender<io::Result<()>>),
+  Rotate(oneshot::Sender<io::Result<u64>>),
+  Sync(oneshot::Sender<io::Result<()>>),
+}
+
+impl WalWriter {
+  /// Starts the thread that writes to `wal`. With `sync`, every batch is flushed before its
+  /// writers are told. Failed changes are undone in `store`, with `queries` locked like for a
+  /// mutating query. The thread stops once every clone of the writer is dropped.
+  pub fn spawn(
+    wal: Arc<Mutex<Wal>>,
+    sync: bool,
+    store: DataStore,
+    queries: Arc<RwLock<()>>,
+  ) -> io::Result<Self> {
+    let (queue, mut requests) = mpsc::channel::<Request>(QUEUE_LEN);
+    let failed = Arc::new(AtomicBool::new(false));
+
+    let writer = WalWriter { queue, failed: failed.clone() };
+    thread::Builder::new().name("wal-writer".to_string()).spawn(move || {
+      while let Some(first) = requests.blocking_recv() {
+        let mut records = Vec::new();
+        let mut undo = Vec::new();
+        let mut waiting = Vec::new();
+        let mut after_batch = None;
+
+        // A rotation ends the batch, so the appends queued before it end up in the old segment.
+        // A sync ends it as well, so it covers them.
+        let mut next = Some(first);
+        while let Some(request) = next {
+          match request {
+            Request::Append(mut changes, written) => {
+              records.push(std::mem::take(&mut changes.logs));
+              undo.push(changes);
+              waiting.push(written);
+            }
+            request => {
+              after_batch = Some(request);
+              break;
+            }
+          }
+          next = if records.len() < MAX_BATCH { requests.try_recv().ok() } else { None };
+        }
+
+        let mut wal = wal.lock().unwrap_or_else(PoisonError::into_inner);
+        if !records.is_empty() {
+          let result = if failed.load(Ordering::SeqCst) {
+            Err(writer_failed())
+          } else {
+            wal.append(&records).and_then(|_| if sync { wal.sync() } else { Ok(()) })
+          };
+
+          if let Err(err) = &result {
+            tracing::error!("WAL write error, undoing the unwritten changes: {:?}", err);
+            // Nothing can be queued while the lock is held, so this catches every change that
+            // was made after the failed ones.
+            let _write = queries.write().unwrap_or_else(PoisonError::into_inner);
+            while let Ok(request) = requests.try_recv() {
+              match request {
+                Request::Append(changes, written) => {
+                  undo.push(changes);
+                  waiting.push(written);
+                }
+                Request::Rotate(rotated) => {
+                  let _ = rotated.send(Err(writer_failed()));
+                }
+                Request::Sync(synced) => {
+                  let _ = synced.send(Err(writer_failed()));
+                }
+              }
+            }
+            for changes in undo.into_iter().rev() {
+              changes.undo(&store);
+            }
+            failed.store(true, Ordering::SeqCst);
+          }
+
+          for written in waiting {
+            let result = match &result {
+              Ok(()) => Ok(()),
+              Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            };
+            // The writer may have stopped waiting.
+            let _ = written.send(result);
+          }
+        }
+        match after_batch {
+          Some(Request::Rotate(rotated)) => {
+            let _ = rotated.send(wal.rotate());
+          }
+          Some(Request::Sync(synced)) => {
+            let _ = synced.send(wal.sync());
+          }
+          _ => {}
+        }
+      }
+    })?;
+
+    Ok(writer)
+  }
+
+  /// Whether a batch failed, after which every write is refused.
+  pub fn failed(&self) -> bool {
+    self.failed.load(Ordering::SeqCst)
+  }
+
+  /// Waits for room in the queue. Call it before locking the queries, so the lock isn't held
+  /// while the queue is full.
+  pub async fn reserve(&self) -> io::Result<WalPermit> {
+    let permit = self.queue.clone().reserve_owned().await.map_err(|_| writer_stopped())?;
+    Ok(WalPermit(permit))
+  }
+
+  /// Queues a [Wal::sync] behind the appends queued so far.
+  pub async fn sync(&self) -> io::Result<()> {
+    let (synced, result) = oneshot::channel();
+    self.queue.send(Request::Sync(synced)).await.map_err(|_| writer_stopped())?;
+    result.await.unwrap_or_else(|_| Err(writer_stopped()))
+  }
+}
+
+impl WalPermit {
+  /// Queues the logs of `changes` as one record right away, so the order of the records is the
+  /// order of the calls. The returned future resolves once the batch with the record was written.
+  pub fn append(self, changes: Changes) -> impl Future<Output = io::Result<()>> {
+    let (written, result) = oneshot::channel();
+    self.0.send(Request::Append(changes, written));
+    async move { result.await.unwrap_or_else(|_| Err(writer_stopped())) }
+  }
+
+  /// Queues a [Wal::rotate] behind the appends queued so far. Resolves to the new segment.
+  pub fn rotate(self) -> impl Future<Output = io::Result<u64>> {
+    let (rotated, result) = oneshot::channel();
+    self.0.send(Request::Rotate(rotated));
+    async move { result.await.unwrap_or_else(|_| Err(writer_stopped())) }
+  }
+}
+
+fn writer_failed() -> io::Error {
+  io::Error::other("An earlier WAL write failed")
+}
+
+fn writer_stopped() -> io::Error {
+  io::Error::new(io::ErrorKind::BrokenPipe, "The WAL writer stopped")
+}
+
+/// Reads the logs of all records. A torn record at the end, left by a crash in the middle of a
+/// write, is cut off the file. A corrupted record before it fails the recovery and leaves the
+/// file as it is, since the records after it can't be found without its length.
+pub fn recover(path: impl AsRef<Path>) -> io::Result<Vec<DataChangeLog>> {
+  let path = path.as_ref();
+  let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(err) => return Err(err),
+  };
+  let mut buf = Vec::new();
+  file.read_to_end(&mut buf)?;
+
+  if buf.is_empty() {
+    return Ok(Vec::new());
+  }
+  if buf.len() < HEADER_LEN && header().starts_with(&buf) {
+    // Nothing but a torn header.
+    truncate(&file, 0, buf.len())?;
+    return Ok(Vec::new());
+  }
+  if !buf.starts_with(&MAGIC) {
+    return upgrade_unversioned(path, &buf);
+  }
+  if buf[MAGIC.len()] != FORMAT_VERSION {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("Unsupported WAL format version {}", buf[MAGIC.len()]),
+    ));
+  }
+
+  let mut logs = Vec::new();
+  let mut offset = HEADER_LEN;
+  while let Some((crc, payload)) = next_record(&buf[offset..]) {
+    let end = offset + RECORD_HEADER_LEN + payload.len();
+    if crc32c::crc32c(payload) != crc {
+      // The last record is only half written, that's left to the truncation below.
+      if end == buf.len() {
+        break;
+      }
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Corrupted WAL record at byte {offset} of {path:?}"),
+      ));
+    } else {
+      // The checksum matches, so the record was written like this. Skipping it would replay the
+      // records after it without its changes.
+      let batch = bincode::deserialize::<Vec<DataChangeLog>>(payload).map_err(|err| {
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("Undecodable WAL record at byte {offset} of {path:?}: {err}"),
+        )
+      })?;
+      logs.extend(batch);
+    }
+    offset = end;
+  }
+
+  if offset < buf.len() {
+    // A torn write leaves nothing intact behind it. An intact record means the length of this
+    // one is corrupted, and cutting it off would lose the records after it.
+    if intact_record_after(&buf[offset..]) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Corrupted WAL record length at byte {offset} of {path:?}"),
+      ));
+    }
+    truncate(&file, offset, buf.len())?;
+  }
+  Ok(logs)
+}
+
+fn header() -> [u8; HEADER_LEN] {
+  let mut header = [0u8; HEADER_LEN];
+  header[..MAGIC.len()].copy_from_slice(&MAGIC);
+  header[MAGIC.len()] = FORMAT_VERSION;
+  header
+}
+
+fn encode_record(buf: &mut Vec<u8>, logs: &[DataChangeLog]) -> io::Result<()> {
+  let payload = bincode::serialize(logs)
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to serialize"))?;
+  let len = u32::try_from(payload.len())
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "WAL record too large"))?;
+
+  buf.extend(len.to_le_bytes());
+  buf.extend(crc32c::crc32c(&payload).to_le_bytes());
+  buf.extend(payload);
+  Ok(())
+}
+
+/// Checksum and bytes of the record at the start of `buf`, or [None] if it isn't complete.
+fn next_record(buf: &[u8]) -> Option<(u32, &[u8])> {
+  let len = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+  let crc = u32::from_le_bytes(buf.get(4..RECORD_HEADER_LEN)?.try_into().ok()?);
+  let payload = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN.checked_add(len)?)?;
+  Some((crc, payload))
+}
+
+/// Whether a complete record with a matching checksum starts anywhere after the record header
+/// at the start of `buf`.
+fn intact_record_after(buf: &[u8]) -> bool {
+  (RECORD_HEADER_LEN..buf.len()).any(|start| {
+    next_record(&buf[start..])
+      .is_some_and(|(crc, payload)| !payload.is_empty() && crc32c::crc32c(payload) == crc)
+  })
+}
+
+fn truncate(file: &File, len: usize, file_len: usize) -> io::Result<()> {
+  tracing::warn!("Cutting a torn record of {} bytes off the end of the WAL", file_len - len);
+  file.set_len(len as u64)?;
+  file.sync_data()
+}
+
+/// A log as written before [FORMAT_VERSION] 1, from before expiries and versions.
+#[derive(Serialize, Deserialize)]
+struct UnversionedLog {
+  query: UnversionedQuery,
+  date: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+enum UnversionedQuery {
+  Put(PlainPutQuery),
+  Delete(PlainDeleteQuery),
+}
+
+/// Reads a WAL of plain concatenated logs, as written before [FORMAT_VERSION] 1, and rewrites it
+/// as a single record, so new records can be appended to it. The logs get versions after
+/// [UNVERSIONED_VERSION], so they are replayed on top of a snapshot of the same age.
+fn upgrade_unversioned(path: &Path, buf: &[u8]) -> io::Result<Vec<DataChangeLog>> {
+  let mut logs = Vec::new();
+  let mut offset = 0;
+  let mut version = UNVERSIONED_VERSION;
+  while offset < buf.len() {
+    // Without checksums a torn write can't be told from garbage, so everything from the first
+    // record that doesn't decode on is cut off.
+    let Ok(log) = bincode::deserialize::<UnversionedLog>(&buf[offset..]) else {
+      tracing::warn!(
+        "Cutting off {} undecodable bytes at the end of {:?}",
+        buf.len() - offset,
+        path
+      );
+      break;
+    };
+    offset += bincode::serialized_size(&log).map_err(io::Error::other)? as usize;
+
+    version += 1;
+    let query = match log.query {
+      UnversionedQuery::Put(query) => DataChangeQuery::Put(query.into()),
+      UnversionedQuery::Delete(query) => DataChangeQuery::Delete(query.into()),
+    };
+    logs.push(DataChangeLog { query, version, date: log.date });
+  }
+
+  let mut upgraded = header().to_vec();
+  encode_record(&mut upgraded, &logs)?;
+
+  utils::write_file_atomic(path, path.with_extension("wal.tmp"), &upgraded)?;
+
+  tracing::info!("Upgraded the WAL to format version {FORMAT_VERSION}");
+  Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::public_api::dataquery::PutQuery;
+
+  fn log(version: u64) -> Vec<DataChangeLog> {
+    let query =
+      PutQuery { key: "a".to_string(), value: vec![1; 16], expiry: None, if_version: None };
+    vec![DataChangeLog { query: DataChangeQuery::Put(query), version, date: 0 }]
+  }
+
+  /// A segment with three records, and the offsets where each record starts.
+  fn segment(dir: &Path) -> (PathBuf, Vec<usize>) {
+    let mut wal = Wal::new(dir);
+    let mut starts = Vec::new();
+    for version in 1..=3 {
+      starts.push(wal.len as usize);
+      wal.append(&[log(version)]).unwrap();
+    }
+    starts[0] = HEADER_LEN;
+    (segment_path(dir, 0), starts)
+  }
+
+  fn versions(logs: &[DataChangeLog]) -> Vec<u64> {
+    logs.iter().map(|log| log.version).collect()
+  }
+
+  #[test]
+  fn cuts_off_torn_tail() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = segment(dir.path());
+    let mut buf = fs::read(&path).unwrap();
+    let len = buf.len();
+    buf.truncate(len - 5);
+    fs::write(&path, &buf).unwrap();
+
+    assert_eq!(versions(&recover(&path).unwrap()), [1, 2]);
+    // The next append starts on a clean record boundary.
+    let mut wal = Wal::new(dir.path());
+    wal.append(&[log(4)]).unwrap();
+    assert_eq!(versions(&recover(&path).unwrap()), [1, 2, 4]);
+  }
+
+  #[test]
+  fn fails_on_corruption_in_the_middle() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, starts) = segment(dir.path());
+    let mut buf = fs::read(&path).unwrap();
+    buf[starts[1] + RECORD_HEADER_LEN] ^= 0xff;
+    fs::write(&path, &buf).unwrap();
+
+    assert!(recover(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), buf);
+  }
+
+  #[test]
+  fn fails_on_corrupted_length_in_the_middle() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, starts) = segment(dir.path());
+    let mut buf = fs::read(&path).unwrap();
+    // Points past the end of the file, like the length of a torn record would.
+    buf[starts[1]..starts[1] + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&path, &buf).unwrap();
+
+    assert!(recover(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), buf);
+  }
+
+  #[test]
+  fn upgrades_unversioned_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.wal");
+    let mut buf = Vec::new();
+    for query in [
+      UnversionedQuery::Put(PlainPutQuery { key: "a".to_string(), value: b"1".to_vec() }),
+      UnversionedQuery::Delete(PlainDeleteQuery { key: "a".to_string() }),
+    ] {
+      buf.extend(bincode::serialize(&UnversionedLog { query, date: 0 }).unwrap());
+    }
+    fs::write(&path, buf).unwrap();
+
+    let logs = recover(&path).unwrap();
+    assert_eq!(logs.iter().map(|log| log.version).collect::<Vec<_>>(), [2, 3]);
+    assert!(matches!(&logs[0].query, DataChangeQuery::Put(put) if put.value == b"1"));
+    assert!(matches!(&logs[1].query, DataChangeQuery::Delete(delete) if delete.key == "a"));
+
+    // The file was rewritten in the current format.
+    assert!(fs::read(&path).unwrap().starts_with(&header()));
+    assert_eq!(recover(&path).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn cuts_off_torn_tail_of_unversioned_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.wal");
+    let mut buf = Vec::new();
+    for key in ["a", "b"] {
+      let query = UnversionedQuery::Delete(PlainDeleteQuery { key: key.to_string() });
+      buf.extend(bincode::serialize(&UnversionedLog { query, date: 0 }).unwrap());
+    }
+    buf.truncate(buf.len() - 3);
+    fs::write(&path, buf).unwrap();
+
+    assert_eq!(versions(&recover(&path).unwrap()), [2]);
+    assert!(fs::read(&path).unwrap().starts_with(&header()));
+    assert_eq!(versions(&recover(&path).unwrap()), [2]);
+  }
+
+  #[test]
+  fn fails_on_undecodable_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = segment(dir.path());
+    let mut buf = fs::read(&path).unwrap();
+    let payload = b"not a batch of logs";
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(crc32c::crc32c(payload).to_le_bytes());
+    buf.extend(payload);
+    fs::write(&path, &buf).unwrap();
+
+    let err = recover(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(fs::read(&path).unwrap(), buf);
+  }
+}
@@ -1,14 +1,38 @@
-use memory_db::{state::State, tcp::server::TcpServer};
+use clap::Parser as _;
+use memory_db::{
+  config::{Args, Config},
+  state::{Acl, State},
+  tcp::server::TcpServer,
+};
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
 #[tokio::main]
 async fn main() {
-  let subscriber = FmtSubscriber::builder().with_max_level(Level::TRACE).finish();
+  let config = Config::load(Args::parse()).expect("Could not load the config");
+  let level: Level = config.log_level.parse().expect("Invalid log level");
+
+  let subscriber = FmtSubscriber::builder().with_max_level(level).finish();
 
   tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-  let mut state = State::default();
+  let mut state = State::new(config.storage.clone());
+  if let Some(node_id) = config.node_id {
+    state = state.with_node_id(node_id);
+  }
+  if let Some(acl_file) = &config.acl_file {
+    state = state.with_acl(Acl::load(acl_file).expect("Could not load the ACL"));
+  }
   state.init().unwrap();
 
-  TcpServer::new("127.0.0.1:8000", state.clone()).run().await;
+  #[cfg(unix)]
+  if let Some(path) = &config.unix_socket {
+    let mut server = memory_db::tcp::unix_server::UnixServer::new(path, state.clone())
+      .with_max_connections(config.max_connections);
+    if let Some(mode) = config.unix_socket_mode {
+      server = server.with_permissions(mode);
+    }
+    tokio::spawn(async move { server.run().await });
+  }
+
+  TcpServer::from_config(&config, state.clone()).expect("Could not set up TLS").run().await;
 }
@@ -1,18 +1,158 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+  collections::{BTreeSet, HashMap},
+  ops::Bound,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, PoisonError, RwLock,
+  },
+};
 
 use bytes::Bytes;
-use dashmap::DashMap;
+use chrono::Utc;
+use dashmap::{mapref::one::Ref, DashMap};
 use serde::{Deserialize, Serialize};
 
+/// Version given to values stored before values had versions. It isn't 0, which `if_version`
+/// conditions take as a missing key.
+pub const UNVERSIONED_VERSION: u64 = 1;
+
 #[derive(Clone, Default)]
-pub struct DataStore(pub Arc<DashMap<DataStoreKey, DataStoreValue>>);
+pub struct DataStore {
+  pub map: Arc<DashMap<DataStoreKey, DataStoreValue>>,
+  /// Version of the last change. Every logged change gets the next version, and values carry
+  /// the version of the change that last wrote them.
+  version: Arc<AtomicU64>,
+  /// The keys of `map` in order, for scans. Every write to `map` happens while this is locked
+  /// exclusively, see [DataStore::with_keys], so the two always hold the same keys.
+  keys: Arc<RwLock<BTreeSet<DataStoreKey>>>,
+}
+
+impl DataStore {
+  pub fn version(&self) -> u64 {
+    self.version.load(Ordering::SeqCst)
+  }
+
+  /// Claims the version for a new change.
+  pub fn next_version(&self) -> u64 {
+    self.version.fetch_add(1, Ordering::SeqCst) + 1
+  }
+
+  /// Moves the version up to `version`, for changes replayed with the version they had.
+  pub(crate) fn advance_version(&self, version: u64) {
+    self.version.fetch_max(version, Ordering::SeqCst);
+  }
+
+  /// The value of `key`, unless it is missing or expired at `now`.
+  pub fn get_live(
+    &self,
+    key: &DataStoreKey,
+    now: i64,
+  ) -> Option<Ref<'_, DataStoreKey, DataStoreValue>> {
+    self.map.get(key).filter(|value| !value.is_expired(now))
+  }
+
+  /// Runs `f` with exclusive access to the ordered keys. Writes to `map` have to go through
+  /// here, and keep `keys` up to date.
+  pub(crate) fn with_keys<R>(&self, f: impl FnOnce(&mut BTreeSet<DataStoreKey>) -> R) -> R {
+    let mut keys = self.keys.write().unwrap_or_else(PoisonError::into_inner);
+    f(&mut keys)
+  }
+
+  pub fn insert(&self, key: DataStoreKey, value: DataStoreValue) {
+    self.with_keys(|keys| {
+      keys.insert(key.clone());
+      self.map.insert(key, value);
+    });
+  }
+
+  pub fn remove(&self, key: &DataStoreKey) -> Option<DataStoreValue> {
+    self.with_keys(|keys| {
+      keys.remove(key);
+      self.map.remove(key).map(|(_, value)| value)
+    })
+  }
+
+  /// Removes every value that expired before `now`.
+  pub fn remove_expired(&self, now: i64) {
+    self.with_keys(|keys| {
+      self.map.retain(|key, value| {
+        let expired = value.is_expired(now);
+        if expired {
+          keys.remove(key);
+        }
+        !expired
+      })
+    });
+  }
+
+  /// Values live at `now` with keys in `lower..`, in key order, as long as `keep` returns true
+  /// for their key. Stops after `limit` values.
+  pub fn range(
+    &self,
+    lower: Bound<DataStoreKey>,
+    keep: impl Fn(&str) -> bool,
+    limit: usize,
+    now: i64,
+  ) -> Vec<(DataStoreKey, DataStoreValue)> {
+    let keys = self.keys.read().unwrap_or_else(PoisonError::into_inner);
+
+    keys
+      .range((lower, Bound::Unbounded))
+      .take_while(|key| keep(&key.0))
+      .filter_map(|key| {
+        let value = self.map.get(key).filter(|value| !value.is_expired(now))?;
+        Some((key.clone(), value.clone()))
+      })
+      .take(limit)
+      .collect()
+  }
+
+  /// Version of the value of `key` live at `now`, or 0 when there is none.
+  pub fn live_version(&self, key: &DataStoreKey, now: i64) -> u64 {
+    self.get_live(key, now).map_or(0, |value| value.version)
+  }
+
+  /// Copies the store, leaving out expired values.
+  pub fn snapshot(&self) -> DataStoreSnapshot {
+    let now = Utc::now().timestamp_millis();
+    // Read the version first, so a concurrent write can't end up in the data with a version the
+    // snapshot claims not to contain.
+    let version = self.version();
+    let data = self
+      .map
+      .iter()
+      .filter(|e| !e.value().is_expired(now))
+      .map(|e| (e.key().clone(), e.value().clone()))
+      .collect();
+
+    DataStoreSnapshot { version, data }
+  }
+}
+
+/// Serialized form of a [DataStore], used for snapshots on disk and in Raft.
+#[derive(Serialize, Deserialize)]
+pub struct DataStoreSnapshot {
+  pub version: u64,
+  pub data: HashMap<DataStoreKey, DataStoreValue>,
+}
+
+impl From<DataStoreSnapshot> for DataStore {
+  fn from(value: DataStoreSnapshot) -> Self {
+    let keys: BTreeSet<DataStoreKey> = value.data.keys().cloned().collect();
+    let dash_map: DashMap<DataStoreKey, DataStoreValue> = value.data.into_iter().collect();
+
+    DataStore {
+      map: Arc::new(dash_map),
+      version: Arc::new(AtomicU64::new(value.version)),
+      keys: Arc::new(RwLock::new(keys)),
+    }
+  }
+}
 
 impl TryFrom<DataStore> for Bytes {
   type Error = ();
   fn try_from(value: DataStore) -> Result<Self, Self::Error> {
-    let hash_map: HashMap<DataStoreKey, DataStoreValue> =
-      value.0.iter().map(|e| (e.key().clone(), e.value().clone())).collect();
-    let bytes: Vec<u8> = bincode::serialize(&hash_map).map_err(|_| ())?;
+    let bytes: Vec<u8> = bincode::serialize(&value.snapshot()).map_err(|_| ())?;
 
     Ok(Bytes::from(bytes))
   }
@@ -21,16 +161,13 @@ impl TryFrom<DataStore> for Bytes {
 impl TryFrom<Bytes> for DataStore {
   type Error = ();
   fn try_from(value: Bytes) -> Result<Self, Self::Error> {
-    let hash_map: HashMap<DataStoreKey, DataStoreValue> =
-      bincode::deserialize(&value.to_vec()).map_err(|_| ())?;
+    let snapshot: DataStoreSnapshot = bincode::deserialize(&value).map_err(|_| ())?;
 
-    let dash_map: DashMap<DataStoreKey, DataStoreValue> = DashMap::from_iter(hash_map.into_iter());
-
-    Ok(DataStore(Arc::new(dash_map)))
+    Ok(snapshot.into())
   }
 }
 
-#[derive(Eq, PartialEq, Hash, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub struct DataStoreKey(pub Arc<str>);
 
 impl<'a> From<&'a str> for DataStoreKey {
@@ -58,36 +195,49 @@ impl<'a> Deserialize<'a> for DataStoreKey {
   }
 }
 
-#[derive(Eq, PartialEq, Hash, Clone)]
-pub struct DataStoreValue(pub Arc<[u8]>);
+#[derive(Eq, PartialEq, Hash, Clone, Serialize, Deserialize)]
+pub struct DataStoreValue {
+  #[serde(serialize_with = "serialize_data", deserialize_with = "deserialize_data")]
+  pub data: Arc<[u8]>,
+  /// Unix timestamp in milliseconds after which the value counts as deleted.
+  pub expires_at: Option<i64>,
+  /// Version of the change that wrote the value, see [DataStore::next_version].
+  pub version: u64,
+}
+
+impl DataStoreValue {
+  pub fn new(data: Vec<u8>, expires_at: Option<i64>, version: u64) -> Self {
+    DataStoreValue { data: Arc::from(data.as_slice()), expires_at, version }
+  }
+
+  pub fn is_expired(&self, now: i64) -> bool {
+    self.expires_at.is_some_and(|expires_at| expires_at <= now)
+  }
+}
 
 impl From<Vec<u8>> for DataStoreValue {
   fn from(value: Vec<u8>) -> Self {
-    DataStoreValue(Arc::from(value.as_slice()))
+    DataStoreValue::new(value, None, 0)
   }
 }
 
 impl<'a> From<&'a [u8]> for DataStoreValue {
   fn from(value: &'a [u8]) -> Self {
-    DataStoreValue(Arc::from(value))
+    DataStoreValue { data: Arc::from(value), expires_at: None, version: 0 }
   }
 }
 
-impl Serialize for DataStoreValue {
-  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-  where
-    S: serde::Serializer,
-  {
-    serializer.serialize_bytes(&self.0)
-  }
+fn serialize_data<S>(data: &Arc<[u8]>, serializer: S) -> Result<S::Ok, S::Error>
+where
+  S: serde::Serializer,
+{
+  serializer.serialize_bytes(data)
 }
 
-impl<'a> Deserialize<'a> for DataStoreValue {
-  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-  where
-    D: serde::Deserializer<'a>,
-  {
-    let test: &[u8] = Deserialize::deserialize(deserializer)?;
-    Ok(test.into())
-  }
+fn deserialize_data<'a, D>(deserializer: D) -> Result<Arc<[u8]>, D::Error>
+where
+  D: serde::Deserializer<'a>,
+{
+  let test: &[u8] = Deserialize::deserialize(deserializer)?;
+  Ok(Arc::from(test))
 }
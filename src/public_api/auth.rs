@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+/// Body of an Auth request. It authenticates the connection it is sent on, every later request
+/// on that connection runs with the permissions of `user`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthQuery {
+  pub user: String,
+  pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthResponse {
+  pub user: String,
+}
@@ -1,10 +1,35 @@
 use crate::tcp::server::CommandV0;
+use dashmap::Entry;
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::{DataStore, DataStoreKey};
+use crate::{
+  log::{Changes, DataChangeQuery},
+  prelude::{DataStore, DataStoreKey, DataStoreValue},
+  public_api::{scan::ScanQuery, transaction::TransactionQuery},
+};
 
 pub trait HandleQuery {
-  fn exec(self, datastore: DataStore) -> Vec<u8>;
+  /// Runs the query at `now`, the Unix timestamp in milliseconds that expiries and conditions
+  /// are checked against. Every change to `datastore` is recorded in `changes`.
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError>;
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+  NotFound,
+  /// The precondition of a conditional write didn't hold, so nothing was written.
+  ConditionFailed,
+  /// The query could not be executed because of a server side failure, like a failed WAL write.
+  Internal(String),
+  /// Authentication is required, but the connection hasn't authenticated.
+  Unauthenticated,
+  /// The authenticated user isn't allowed to run the query.
+  Forbidden(String),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -12,16 +37,68 @@ pub struct ReadQuery {
   pub key: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadResponse {
+  pub value: Vec<u8>,
+  /// Pass this as `if_version` to only overwrite the value if nobody else did in the meantime.
+  pub version: u64,
+  /// Unix timestamp in milliseconds after which the value expires, if it has a TTL.
+  pub expires_at: Option<i64>,
+}
+
+impl From<&DataStoreValue> for ReadResponse {
+  fn from(value: &DataStoreValue) -> Self {
+    ReadResponse {
+      value: value.data.to_vec(),
+      version: value.version,
+      expires_at: value.expires_at,
+    }
+  }
+}
+
 impl HandleQuery for ReadQuery {
-  fn exec(self, datastore: DataStore) -> Vec<u8> {
+  fn exec(self, datastore: DataStore, now: i64, _: &mut Changes) -> Result<Vec<u8>, QueryError> {
     let Self { key } = self;
 
-    if let Some(value) = datastore.0.get(&key.as_str().into()) {
-      let mut value = value.0.to_vec();
-      value.extend("\n".as_bytes().to_vec());
-      value
+    if let Some(value) = datastore.get_live(&key.as_str().into(), now) {
+      let response = ReadResponse::from(&*value);
+      bincode::serialize(&response).map_err(|err| QueryError::Internal(err.to_string()))
     } else {
-      "NOT FOUND\n".as_bytes().to_vec()
+      Err(QueryError::NotFound)
+    }
+  }
+}
+
+/// Version of the live value in `entry`, or 0 when there is none.
+fn live_version(entry: &Entry<'_, DataStoreKey, DataStoreValue>, now: i64) -> u64 {
+  match entry {
+    Entry::Occupied(entry) if !entry.get().is_expired(now) => entry.get().version,
+    _ => 0,
+  }
+}
+
+/// The value in `entry`, expired or not.
+fn current_value(entry: &Entry<'_, DataStoreKey, DataStoreValue>) -> Option<DataStoreValue> {
+  match entry {
+    Entry::Occupied(entry) => Some(entry.get().clone()),
+    Entry::Vacant(_) => None,
+  }
+}
+
+/// When a put value stops being readable.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Expiry {
+  /// Milliseconds from the moment the server handles the put.
+  Ttl(u64),
+  /// Unix timestamp in milliseconds.
+  At(i64),
+}
+
+impl Expiry {
+  pub fn expires_at(self, now: i64) -> i64 {
+    match self {
+      Expiry::Ttl(ttl) => now.saturating_add(ttl.try_into().unwrap_or(i64::MAX)),
+      Expiry::At(at) => at,
     }
   }
 }
@@ -30,28 +107,372 @@ impl HandleQuery for ReadQuery {
 pub struct PutQuery {
   pub key: String,
   pub value: Vec<u8>,
+  pub expiry: Option<Expiry>,
+  /// Only put if the key is currently at this version. 0 means the key must not exist.
+  pub if_version: Option<u64>,
+}
+
+/// Body of [CommandV0::Put], which predates expiries and versions. Puts with those are sent as
+/// [CommandV0::PutWithOptions].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlainPutQuery {
+  pub key: String,
+  pub value: Vec<u8>,
+}
+
+impl From<PlainPutQuery> for PutQuery {
+  fn from(value: PlainPutQuery) -> Self {
+    PutQuery { key: value.key, value: value.value, expiry: None, if_version: None }
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PutResponse {
+  /// Version the value was written with.
+  pub version: u64,
+}
+
+impl PutQuery {
+  /// Turns a relative TTL into an absolute expiry, so replaying the query from the WAL later
+  /// doesn't extend the lifetime of the value.
+  pub fn pin_expiry(&mut self, now: i64) {
+    self.expiry = self.expiry.map(|expiry| Expiry::At(expiry.expires_at(now)));
+  }
+
+  pub fn condition_holds(&self, datastore: &DataStore, now: i64) -> bool {
+    self
+      .if_version
+      .is_none_or(|version| datastore.live_version(&self.key.as_str().into(), now) == version)
+  }
+
+  /// The query as it is logged once its condition held: without the condition, and with the
+  /// expiry pinned to `now`.
+  pub fn as_logged(&self, now: i64) -> PutQuery {
+    let mut logged = PutQuery { if_version: None, ..self.clone() };
+    logged.pin_expiry(now);
+    logged
+  }
+
+  /// Writes the value with the given version, without checking `if_version`.
+  pub(crate) fn apply(self, datastore: &DataStore, version: u64, now: i64) {
+    let Self { key, value, expiry, .. } = self;
+    let expires_at = expiry.map(|expiry| expiry.expires_at(now));
+
+    datastore.insert(key.as_str().into(), DataStoreValue::new(value, expires_at, version));
+  }
 }
 
 impl HandleQuery for PutQuery {
-  fn exec(self, datastore: DataStore) -> Vec<u8> {
-    let Self { key, value } = self;
-    datastore.0.insert(key.as_str().into(), value.into());
-    "OK\n".as_bytes().to_vec()
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
+    let version = self.put(&datastore, now, changes)?;
+
+    bincode::serialize(&PutResponse { version })
+      .map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+impl PutQuery {
+  /// Checks `if_version` and writes the value. Returns the version it was written with.
+  fn put(self, datastore: &DataStore, now: i64, changes: &mut Changes) -> Result<u64, QueryError> {
+    let logged = self.as_logged(now);
+    let Self { key, value, expiry, if_version } = self;
+    let expires_at = expiry.map(|expiry| expiry.expires_at(now));
+
+    let version = datastore.with_keys(|keys| {
+      // The entry keeps the key locked between the version check and the write.
+      let entry = datastore.map.entry(key.as_str().into());
+      if if_version.is_some_and(|version| live_version(&entry, now) != version) {
+        return Err(QueryError::ConditionFailed);
+      }
+
+      let version = datastore.next_version();
+      changes.replaced(entry.key().clone(), current_value(&entry));
+      keys.insert(entry.key().clone());
+      entry.insert(DataStoreValue::new(value, expires_at, version));
+      Ok(version)
+    })?;
+    changes.log(DataChangeQuery::Put(logged), version, now);
+    Ok(version)
   }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DeleteQuery {
   pub key: String,
+  /// Only delete if the key is currently at this version.
+  pub if_version: Option<u64>,
+}
+
+/// Body of [CommandV0::Delete]. Deletes with a condition are sent as
+/// [CommandV0::DeleteWithOptions].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlainDeleteQuery {
+  pub key: String,
+}
+
+impl From<PlainDeleteQuery> for DeleteQuery {
+  fn from(value: PlainDeleteQuery) -> Self {
+    DeleteQuery { key: value.key, if_version: None }
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeleteResponse {
+  /// Version of the delete.
+  pub version: u64,
+  /// Whether there was a live value to delete.
+  pub existed: bool,
+}
+
+impl DeleteQuery {
+  pub fn condition_holds(&self, datastore: &DataStore, now: i64) -> bool {
+    self
+      .if_version
+      .is_none_or(|version| datastore.live_version(&self.key.as_str().into(), now) == version)
+  }
+
+  /// Deletes the key, without checking `if_version`.
+  pub(crate) fn apply(self, datastore: &DataStore) {
+    datastore.remove(&DataStoreKey::from(self.key.as_str()));
+  }
 }
 
 impl HandleQuery for DeleteQuery {
-  fn exec(self, datastore: DataStore) -> Vec<u8> {
-    let Self { key } = self;
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
+    let response = self.delete(&datastore, now, changes)?;
+    bincode::serialize(&response).map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+impl DeleteQuery {
+  /// Checks `if_version` and deletes the key.
+  fn delete(
+    self,
+    datastore: &DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<DeleteResponse, QueryError> {
+    let Self { key, if_version } = self;
 
-    datastore.0.remove(&DataStoreKey::from(key.as_str()));
+    let response = datastore.with_keys(|keys| {
+      let entry = datastore.map.entry(key.as_str().into());
+      if if_version.is_some_and(|version| live_version(&entry, now) != version) {
+        return Err(QueryError::ConditionFailed);
+      }
 
-    "OK\n".as_bytes().to_vec()
+      // Deletes are logged too, so they take up a version even though no value carries it.
+      let version = datastore.next_version();
+      let existed = match entry {
+        Entry::Occupied(entry) => {
+          keys.remove(entry.key());
+          let (key, previous) = entry.remove_entry();
+          let existed = !previous.is_expired(now);
+          changes.replaced(key, Some(previous));
+          existed
+        }
+        Entry::Vacant(_) => false,
+      };
+
+      Ok(DeleteResponse { version, existed })
+    })?;
+    let logged = DeleteQuery { key, if_version: None };
+    changes.log(DataChangeQuery::Delete(logged), response.version, now);
+    Ok(response)
+  }
+}
+
+/// What a [CasQuery] expects the current value of its key to be.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CasExpectation {
+  /// The key must not exist.
+  Missing,
+  /// The key must hold exactly this value.
+  Value(Vec<u8>),
+  /// The key must be at this version.
+  Version(u64),
+}
+
+impl CasExpectation {
+  fn matches(&self, current: Option<&DataStoreValue>) -> bool {
+    match (self, current) {
+      (CasExpectation::Missing, current) => current.is_none(),
+      (CasExpectation::Value(expected), Some(current)) => *expected == *current.data,
+      (CasExpectation::Version(expected), Some(current)) => *expected == current.version,
+      (CasExpectation::Value(_) | CasExpectation::Version(_), None) => false,
+    }
+  }
+}
+
+/// Compare-and-swap: puts `value` only if the key currently matches `expected`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CasQuery {
+  pub key: String,
+  pub expected: CasExpectation,
+  pub value: Vec<u8>,
+  pub expiry: Option<Expiry>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CasResponse {
+  pub swapped: bool,
+}
+
+impl CasQuery {
+  /// The put that a successful swap amounts to, as it is logged.
+  pub fn as_logged_put(&self, now: i64) -> PutQuery {
+    let put = PutQuery {
+      key: self.key.clone(),
+      value: self.value.clone(),
+      expiry: self.expiry,
+      if_version: None,
+    };
+    put.as_logged(now)
+  }
+}
+
+impl HandleQuery for CasQuery {
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
+    let logged = self.as_logged_put(now);
+    let Self { key, expected, value, expiry } = self;
+    let expires_at = expiry.map(|expiry| expiry.expires_at(now));
+
+    // The entry keeps the key locked between the comparison and the swap.
+    let swapped_version = datastore.with_keys(|keys| {
+      let entry = datastore.map.entry(key.as_str().into());
+      let current = match &entry {
+        Entry::Occupied(entry) => Some(entry.get()).filter(|v| !v.is_expired(now)),
+        Entry::Vacant(_) => None,
+      };
+      if !expected.matches(current) {
+        return None;
+      }
+
+      let version = datastore.next_version();
+      changes.replaced(entry.key().clone(), current_value(&entry));
+      keys.insert(entry.key().clone());
+      entry.insert(DataStoreValue::new(value, expires_at, version));
+      Some(version)
+    });
+    if let Some(version) = swapped_version {
+      changes.log(DataChangeQuery::Put(logged), version, now);
+    }
+
+    bincode::serialize(&CasResponse { swapped: swapped_version.is_some() })
+      .map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MultiReadQuery {
+  pub keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MultiReadResponse {
+  /// One entry per key, in the same order. [None] if the key doesn't exist.
+  pub values: Vec<Option<ReadResponse>>,
+}
+
+impl HandleQuery for MultiReadQuery {
+  fn exec(self, datastore: DataStore, now: i64, _: &mut Changes) -> Result<Vec<u8>, QueryError> {
+    let values = self
+      .keys
+      .iter()
+      .map(|key| {
+        let value = datastore.get_live(&key.as_str().into(), now);
+        value.map(|value| ReadResponse::from(&*value))
+      })
+      .collect();
+
+    bincode::serialize(&MultiReadResponse { values })
+      .map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+/// Outcome of a single write in a [MultiPutQuery] or [MultiDeleteQuery].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum WriteResult {
+  Written {
+    version: u64,
+  },
+  /// The `if_version` condition didn't hold, so this write was skipped.
+  ConditionFailed,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MultiWriteResponse {
+  /// One entry per write, in the same order.
+  pub results: Vec<WriteResult>,
+}
+
+/// Puts that are executed one after another, each with its own version. Unlike a
+/// [TransactionQuery] a failed condition only skips that one put. Each condition is checked
+/// right before its put, so it sees the puts before it in the batch.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MultiPutQuery {
+  pub puts: Vec<PutQuery>,
+}
+
+impl HandleQuery for MultiPutQuery {
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
+    let mut results = Vec::with_capacity(self.puts.len());
+    for query in self.puts {
+      results.push(match query.put(&datastore, now, changes) {
+        Ok(version) => WriteResult::Written { version },
+        Err(QueryError::ConditionFailed) => WriteResult::ConditionFailed,
+        Err(err) => return Err(err),
+      });
+    }
+
+    bincode::serialize(&MultiWriteResponse { results })
+      .map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+/// Deletes that are executed like the puts of a [MultiPutQuery].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MultiDeleteQuery {
+  pub deletes: Vec<DeleteQuery>,
+}
+
+impl HandleQuery for MultiDeleteQuery {
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
+    let mut results = Vec::with_capacity(self.deletes.len());
+    for query in self.deletes {
+      results.push(match query.delete(&datastore, now, changes) {
+        Ok(response) => WriteResult::Written { version: response.version },
+        Err(QueryError::ConditionFailed) => WriteResult::ConditionFailed,
+        Err(err) => return Err(err),
+      });
+    }
+
+    bincode::serialize(&MultiWriteResponse { results })
+      .map_err(|err| QueryError::Internal(err.to_string()))
   }
 }
 
@@ -60,20 +481,95 @@ pub enum DataQuery {
   Read(ReadQuery),
   Put(PutQuery),
   Delete(DeleteQuery),
+  CompareAndSwap(CasQuery),
+  Transaction(TransactionQuery),
+  MultiRead(MultiReadQuery),
+  MultiPut(MultiPutQuery),
+  MultiDelete(MultiDeleteQuery),
+  Scan(ScanQuery),
 }
 
-impl HandleQuery for Vec<DataQuery> {
-  fn exec(self, datastore: DataStore) -> Vec<u8> {
-    self.into_iter().flat_map(|query| query.exec(datastore.clone())).collect()
+impl DataQuery {
+  /// See [PutQuery::pin_expiry].
+  pub fn pin_expiry(&mut self, now: i64) {
+    match self {
+      DataQuery::Put(query) => query.pin_expiry(now),
+      DataQuery::CompareAndSwap(query) => {
+        query.expiry = query.expiry.map(|expiry| Expiry::At(expiry.expires_at(now)));
+      }
+      DataQuery::Transaction(query) => query.pin_expiry(now),
+      DataQuery::MultiPut(query) => query.puts.iter_mut().for_each(|put| put.pin_expiry(now)),
+      DataQuery::Read(_)
+      | DataQuery::Delete(_)
+      | DataQuery::MultiRead(_)
+      | DataQuery::MultiDelete(_)
+      | DataQuery::Scan(_) => {}
+    }
+  }
+
+  /// The command ACLs know this query by. Puts and deletes count as [CommandV0::Put] and
+  /// [CommandV0::Delete], whether they are sent with options or not.
+  pub fn command(&self) -> CommandV0 {
+    match self {
+      DataQuery::Read(_) => CommandV0::Get,
+      DataQuery::Put(_) => CommandV0::Put,
+      DataQuery::Delete(_) => CommandV0::Delete,
+      DataQuery::CompareAndSwap(_) => CommandV0::CompareAndSwap,
+      DataQuery::Transaction(_) => CommandV0::Transaction,
+      DataQuery::MultiRead(_) => CommandV0::MultiGet,
+      DataQuery::MultiPut(_) => CommandV0::MultiPut,
+      DataQuery::MultiDelete(_) => CommandV0::MultiDelete,
+      DataQuery::Scan(_) => CommandV0::Scan,
+    }
+  }
+
+  /// Every key the query may read or write. A scan covers all keys starting with its prefix.
+  pub fn keys(&self) -> Vec<&str> {
+    match self {
+      DataQuery::Read(query) => vec![&query.key],
+      DataQuery::Put(query) => vec![&query.key],
+      DataQuery::Delete(query) => vec![&query.key],
+      DataQuery::CompareAndSwap(query) => vec![&query.key],
+      DataQuery::Transaction(query) => query.keys(),
+      DataQuery::MultiRead(query) => query.keys.iter().map(String::as_str).collect(),
+      DataQuery::MultiPut(query) => query.puts.iter().map(|put| put.key.as_str()).collect(),
+      DataQuery::MultiDelete(query) => {
+        query.deletes.iter().map(|delete| delete.key.as_str()).collect()
+      }
+      DataQuery::Scan(query) => vec![query.prefix.as_deref().unwrap_or_default()],
+    }
+  }
+
+  pub fn is_read_only(&self) -> bool {
+    match self {
+      DataQuery::Read(_) | DataQuery::MultiRead(_) | DataQuery::Scan(_) => true,
+      DataQuery::Transaction(query) => !query.has_writes(),
+      DataQuery::Put(_)
+      | DataQuery::Delete(_)
+      | DataQuery::CompareAndSwap(_)
+      | DataQuery::MultiPut(_)
+      | DataQuery::MultiDelete(_) => false,
+    }
   }
 }
 
 impl HandleQuery for DataQuery {
-  fn exec(self, datastore: DataStore) -> Vec<u8> {
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
     match self {
-      DataQuery::Put(query) => query.exec(datastore),
-      DataQuery::Read(query) => query.exec(datastore),
-      DataQuery::Delete(query) => query.exec(datastore),
+      DataQuery::Put(query) => query.exec(datastore, now, changes),
+      DataQuery::Read(query) => query.exec(datastore, now, changes),
+      DataQuery::Delete(query) => query.exec(datastore, now, changes),
+      DataQuery::CompareAndSwap(query) => query.exec(datastore, now, changes),
+      DataQuery::Transaction(query) => query.exec(datastore, now, changes),
+      DataQuery::MultiRead(query) => query.exec(datastore, now, changes),
+      DataQuery::MultiPut(query) => query.exec(datastore, now, changes),
+      DataQuery::MultiDelete(query) => query.exec(datastore, now, changes),
+      DataQuery::Scan(query) => query.exec(datastore, now, changes),
     }
   }
 }
@@ -91,15 +587,120 @@ impl TryFrom<(CommandV0, Vec<u8>)> for DataQuery {
       }
 
       CommandV0::Put => {
+        let query: PlainPutQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::Put(query.into())
+      }
+      CommandV0::PutWithOptions => {
         let query: PutQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
         DataQuery::Put(query)
       }
       CommandV0::Delete => {
+        let query: PlainDeleteQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::Delete(query.into())
+      }
+      CommandV0::DeleteWithOptions => {
         let query: DeleteQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
         DataQuery::Delete(query)
       }
-      _ => unreachable!(),
+      CommandV0::CompareAndSwap => {
+        let query: CasQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::CompareAndSwap(query)
+      }
+      CommandV0::Transaction => {
+        let query: TransactionQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::Transaction(query)
+      }
+      CommandV0::MultiGet => {
+        let query: MultiReadQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::MultiRead(query)
+      }
+      CommandV0::MultiPut => {
+        let query: MultiPutQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::MultiPut(query)
+      }
+      CommandV0::MultiDelete => {
+        let query: MultiDeleteQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::MultiDelete(query)
+      }
+      CommandV0::Scan => {
+        let query: ScanQuery = bincode::deserialize(&body).map_err(|_| InvalidBody)?;
+        DataQuery::Scan(query)
+      }
+      // Ping and Auth are answered by the server itself and never become a query.
+      CommandV0::Ping | CommandV0::Auth => return Err(InvalidBody),
     };
     Ok(value)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::log::DataChangeLog;
+
+  #[test]
+  fn multi_put_conditions_see_earlier_puts() {
+    let store = DataStore::default();
+    let put =
+      PutQuery { key: "a".to_string(), value: b"v".to_vec(), expiry: None, if_version: Some(0) };
+    let query = MultiPutQuery { puts: vec![put.clone(), put] };
+
+    let mut changes = Changes::default();
+    let body = query.exec(store.clone(), 0, &mut changes).unwrap();
+    let response: MultiWriteResponse = bincode::deserialize(&body).unwrap();
+
+    assert!(matches!(
+      response.results.as_slice(),
+      [WriteResult::Written { version: 1 }, WriteResult::ConditionFailed]
+    ));
+    assert_eq!(changes.into_logs().len(), 1);
+  }
+
+  /// Runs a swap of `key` to "new" at `now`, and returns whether it swapped and what it logged.
+  fn cas(store: &DataStore, expected: CasExpectation, now: i64) -> (bool, Vec<DataChangeLog>) {
+    let query = CasQuery { key: "k".to_string(), expected, value: b"new".to_vec(), expiry: None };
+    let mut changes = Changes::default();
+    let body = query.exec(store.clone(), now, &mut changes).unwrap();
+    let response: CasResponse = bincode::deserialize(&body).unwrap();
+    (response.swapped, changes.into_logs())
+  }
+
+  #[test]
+  fn cas_swaps_only_on_expected_values() {
+    let store = DataStore::default();
+    assert!(!cas(&store, CasExpectation::Value(b"old".to_vec()), 0).0);
+    assert!(!cas(&store, CasExpectation::Version(1), 0).0);
+    assert!(cas(&store, CasExpectation::Missing, 0).0);
+
+    // "k" is now "new" at version 1.
+    assert!(!cas(&store, CasExpectation::Missing, 0).0);
+    assert!(!cas(&store, CasExpectation::Value(b"old".to_vec()), 0).0);
+    assert!(!cas(&store, CasExpectation::Version(2), 0).0);
+    assert!(cas(&store, CasExpectation::Value(b"new".to_vec()), 0).0);
+    assert!(cas(&store, CasExpectation::Version(2), 0).0);
+    assert_eq!(store.get_live(&"k".into(), 0).unwrap().version, 3);
+  }
+
+  #[test]
+  fn cas_takes_expired_keys_as_missing() {
+    let store = DataStore::default();
+    store.insert("k".into(), DataStoreValue::new(b"new".to_vec(), Some(5), 1));
+
+    assert!(!cas(&store, CasExpectation::Value(b"new".to_vec()), 10).0);
+    assert!(!cas(&store, CasExpectation::Version(1), 10).0);
+    assert!(cas(&store, CasExpectation::Missing, 10).0);
+  }
+
+  #[test]
+  fn cas_logs_only_swaps() {
+    let store = DataStore::default();
+    let (swapped, logs) = cas(&store, CasExpectation::Version(1), 0);
+    assert!(!swapped && logs.is_empty());
+
+    let (swapped, logs) = cas(&store, CasExpectation::Missing, 0);
+    assert!(swapped);
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].version, 1);
+    assert!(matches!(&logs[0].query, DataChangeQuery::Put(put) if put.value == b"new"));
+  }
+}
@@ -1 +1,5 @@
+pub mod auth;
 pub mod dataquery;
+pub mod ping;
+pub mod scan;
+pub mod transaction;
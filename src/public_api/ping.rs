@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Body of the response to a Ping. Ping doesn't touch the data, so it is cheap enough to use as
+/// a health check.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PingResponse {
+  /// Version of the memory-db server.
+  pub version: String,
+  pub node_id: u64,
+  pub uptime_ms: u64,
+  /// [None] when the node isn't part of a Raft cluster.
+  pub raft_role: Option<RaftRole>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RaftRole {
+  Follower,
+  Candidate,
+  Leader,
+  PreCandidate,
+}
+
+impl From<raft::StateRole> for RaftRole {
+  fn from(value: raft::StateRole) -> Self {
+    match value {
+      raft::StateRole::Follower => RaftRole::Follower,
+      raft::StateRole::Candidate => RaftRole::Candidate,
+      raft::StateRole::Leader => RaftRole::Leader,
+      raft::StateRole::PreCandidate => RaftRole::PreCandidate,
+    }
+  }
+}
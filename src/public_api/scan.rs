@@ -0,0 +1,176 @@
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  log::Changes,
+  prelude::{DataStore, DataStoreKey},
+  public_api::dataquery::{HandleQuery, QueryError},
+};
+
+/// Page size of a [ScanQuery] without a `limit`.
+pub const SCAN_DEFAULT_LIMIT: u32 = 100;
+/// Larger limits are lowered to this, so a single response stays reasonably small.
+pub const SCAN_MAX_LIMIT: u32 = 10_000;
+
+/// Lists live keys in order. All filters are optional and combine: only keys that start with
+/// `prefix`, are `>= start` and `< end` are returned.
+///
+/// If there are more keys than `limit`, the response has a `cursor`. Sending the same query with
+/// that cursor returns the next page.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScanQuery {
+  pub prefix: Option<String>,
+  pub start: Option<String>,
+  pub end: Option<String>,
+  /// [SCAN_DEFAULT_LIMIT] if [None] or 0.
+  pub limit: Option<u32>,
+  /// Only return keys after this one.
+  pub cursor: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScanEntry {
+  pub key: String,
+  pub value: Vec<u8>,
+  pub version: u64,
+  pub expires_at: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScanResponse {
+  pub entries: Vec<ScanEntry>,
+  /// Set when there are more keys, see [ScanQuery].
+  pub cursor: Option<String>,
+}
+
+impl ScanQuery {
+  /// Page size to use. A limit of 0 would never make progress, so it gets the default as well.
+  fn limit(&self) -> usize {
+    match self.limit {
+      None | Some(0) => SCAN_DEFAULT_LIMIT as usize,
+      Some(limit) => limit.min(SCAN_MAX_LIMIT) as usize,
+    }
+  }
+
+  /// The tightest of the lower bounds given by `start`, `prefix` and `cursor`.
+  fn lower_bound(&self) -> Bound<DataStoreKey> {
+    let included = [&self.start, &self.prefix].into_iter().flatten().max();
+
+    match (included, &self.cursor) {
+      (Some(included), Some(cursor)) if included > cursor => {
+        Bound::Included(included.as_str().into())
+      }
+      (_, Some(cursor)) => Bound::Excluded(cursor.as_str().into()),
+      (Some(included), None) => Bound::Included(included.as_str().into()),
+      (None, None) => Bound::Unbounded,
+    }
+  }
+
+  /// Whether `key` is within the upper bounds given by `prefix` and `end`. Prefixed keys are
+  /// contiguous in order, so the scan can stop at the first key without it.
+  fn keep(&self, key: &str) -> bool {
+    self.prefix.as_ref().is_none_or(|prefix| key.starts_with(prefix.as_str()))
+      && self.end.as_ref().is_none_or(|end| key < end.as_str())
+  }
+}
+
+impl HandleQuery for ScanQuery {
+  fn exec(self, datastore: DataStore, now: i64, _: &mut Changes) -> Result<Vec<u8>, QueryError> {
+    let limit = self.limit();
+
+    // One more than needed, to know if there is another page.
+    let mut values = datastore.range(self.lower_bound(), |key| self.keep(key), limit + 1, now);
+    let has_more = values.len() > limit;
+    values.truncate(limit);
+
+    let entries: Vec<ScanEntry> = values
+      .into_iter()
+      .map(|(key, value)| ScanEntry {
+        key: key.0.to_string(),
+        value: value.data.to_vec(),
+        version: value.version,
+        expires_at: value.expires_at,
+      })
+      .collect();
+    let cursor = if has_more { entries.last().map(|entry| entry.key.clone()) } else { None };
+
+    bincode::serialize(&ScanResponse { entries, cursor })
+      .map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::prelude::DataStoreValue;
+
+  fn scan(prefix: Option<&str>, start: Option<&str>, end: Option<&str>) -> ScanQuery {
+    let to_string = |bound: Option<&str>| bound.map(str::to_string);
+    ScanQuery {
+      prefix: to_string(prefix),
+      start: to_string(start),
+      end: to_string(end),
+      ..Default::default()
+    }
+  }
+
+  fn key(key: &str) -> DataStoreKey {
+    key.into()
+  }
+
+  #[test]
+  fn lower_bound_is_the_tightest_bound() {
+    assert_eq!(scan(None, None, None).lower_bound(), Bound::Unbounded);
+    assert_eq!(scan(Some("b"), Some("a"), None).lower_bound(), Bound::Included(key("b")));
+    assert_eq!(scan(Some("b"), Some("c"), None).lower_bound(), Bound::Included(key("c")));
+
+    let after =
+      |cursor: &str| ScanQuery { cursor: Some(cursor.to_string()), ..scan(Some("b"), None, None) };
+    // The cursor is after the prefix once the first page is through.
+    assert_eq!(after("b1").lower_bound(), Bound::Excluded(key("b1")));
+    assert_eq!(after("a").lower_bound(), Bound::Included(key("b")));
+  }
+
+  #[test]
+  fn keeps_keys_within_prefix_and_end() {
+    let query = scan(Some("b"), None, Some("b5"));
+    assert!(query.keep("b") && query.keep("b4"));
+    assert!(!query.keep("b5") && !query.keep("c") && !query.keep("a"));
+    assert!(scan(None, None, None).keep("anything"));
+  }
+
+  #[test]
+  fn pages_through_all_keys() {
+    let store = DataStore::default();
+    for key in ["a", "b1", "b2", "b3", "b4", "b5", "c"] {
+      store.insert(key.into(), DataStoreValue::new(b"v".to_vec(), None, 1));
+    }
+
+    let mut query = ScanQuery { limit: Some(2), ..scan(Some("b"), None, Some("b5")) };
+    let mut pages = Vec::new();
+    loop {
+      let body = query.clone().exec(store.clone(), 0, &mut Changes::default()).unwrap();
+      let response: ScanResponse = bincode::deserialize(&body).unwrap();
+      pages.push(response.entries.into_iter().map(|entry| entry.key).collect::<Vec<_>>());
+      match response.cursor {
+        Some(cursor) => query.cursor = Some(cursor),
+        None => break,
+      }
+    }
+
+    assert_eq!(pages, [vec!["b1", "b2"], vec!["b3", "b4"]]);
+  }
+
+  #[test]
+  fn limit_of_zero_is_the_default() {
+    assert_eq!(
+      ScanQuery { limit: Some(0), ..Default::default() }.limit(),
+      SCAN_DEFAULT_LIMIT as usize
+    );
+    assert_eq!(
+      ScanQuery { limit: Some(u32::MAX), ..Default::default() }.limit(),
+      SCAN_MAX_LIMIT as usize
+    );
+  }
+}
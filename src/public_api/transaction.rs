@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  log::{Changes, DataChangeQuery},
+  prelude::{DataStore, DataStoreKey, DataStoreValue},
+  public_api::dataquery::{
+    DeleteQuery, HandleQuery, PutQuery, QueryError, ReadQuery, ReadResponse,
+  },
+  tcp::server::CommandV0,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TransactionOp {
+  Read(ReadQuery),
+  Put(PutQuery),
+  Delete(DeleteQuery),
+  /// Writes nothing, but aborts the transaction unless `key` is at `version`. Version 0 means
+  /// the key must not exist.
+  Check {
+    key: String,
+    version: u64,
+  },
+}
+
+impl TransactionOp {
+  /// The command that does the same on its own. A check reads the version, so it counts as a
+  /// Get.
+  pub fn command(&self) -> CommandV0 {
+    match self {
+      TransactionOp::Read(_) | TransactionOp::Check { .. } => CommandV0::Get,
+      TransactionOp::Put(_) => CommandV0::Put,
+      TransactionOp::Delete(_) => CommandV0::Delete,
+    }
+  }
+}
+
+/// Operations that are applied all together or not at all. Every condition, that is each
+/// [TransactionOp::Check] and `if_version`, is checked against the store as it was before the
+/// transaction. If any of them fails, nothing is written and the whole transaction fails with
+/// [QueryError::ConditionFailed]. Otherwise the operations run in order, so reads see the writes
+/// of earlier operations.
+///
+/// All writes share one version and one WAL record. Executing it is only atomic if no other
+/// query runs in the meantime, which [crate::state::State] makes sure of.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionQuery {
+  pub operations: Vec<TransactionOp>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OperationResult {
+  /// [None] if the key doesn't exist.
+  Read(Option<ReadResponse>),
+  Put {
+    version: u64,
+  },
+  Delete,
+  Check,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionResponse {
+  /// One result per operation, in the same order.
+  pub results: Vec<OperationResult>,
+}
+
+impl TransactionQuery {
+  pub fn has_writes(&self) -> bool {
+    self.operations.iter().any(|op| matches!(op, TransactionOp::Put(_) | TransactionOp::Delete(_)))
+  }
+
+  pub fn keys(&self) -> Vec<&str> {
+    self
+      .operations
+      .iter()
+      .map(|op| match op {
+        TransactionOp::Read(query) => query.key.as_str(),
+        TransactionOp::Put(query) => query.key.as_str(),
+        TransactionOp::Delete(query) => query.key.as_str(),
+        TransactionOp::Check { key, .. } => key.as_str(),
+      })
+      .collect()
+  }
+
+  pub fn conditions_hold(&self, datastore: &DataStore, now: i64) -> bool {
+    self.operations.iter().all(|op| match op {
+      TransactionOp::Read(_) => true,
+      TransactionOp::Put(query) => query.condition_holds(datastore, now),
+      TransactionOp::Delete(query) => query.condition_holds(datastore, now),
+      TransactionOp::Check { key, version } => {
+        datastore.live_version(&key.as_str().into(), now) == *version
+      }
+    })
+  }
+
+  /// See [PutQuery::pin_expiry].
+  pub fn pin_expiry(&mut self, now: i64) {
+    for op in &mut self.operations {
+      if let TransactionOp::Put(query) = op {
+        query.pin_expiry(now);
+      }
+    }
+  }
+
+  /// Only the writes of the transaction, with their conditions dropped and expiries pinned to
+  /// `now`. This is what gets logged once the conditions are known to hold.
+  pub fn writes(&self, now: i64) -> TransactionQuery {
+    let operations = self
+      .operations
+      .iter()
+      .filter_map(|op| match op {
+        TransactionOp::Put(query) => Some(TransactionOp::Put(query.as_logged(now))),
+        TransactionOp::Delete(query) => {
+          Some(TransactionOp::Delete(DeleteQuery { if_version: None, ..query.clone() }))
+        }
+        TransactionOp::Read(_) | TransactionOp::Check { .. } => None,
+      })
+      .collect();
+
+    TransactionQuery { operations }
+  }
+}
+
+impl HandleQuery for TransactionQuery {
+  fn exec(
+    self,
+    datastore: DataStore,
+    now: i64,
+    changes: &mut Changes,
+  ) -> Result<Vec<u8>, QueryError> {
+    if !self.conditions_hold(&datastore, now) {
+      return Err(QueryError::ConditionFailed);
+    }
+
+    // All writes go into a single log, so they are replayed all or nothing.
+    let version = if self.has_writes() {
+      let version = datastore.next_version();
+      changes.log(DataChangeQuery::Transaction(self.writes(now)), version, now);
+      version
+    } else {
+      datastore.version()
+    };
+
+    let results = self
+      .operations
+      .into_iter()
+      .map(|op| match op {
+        TransactionOp::Read(query) => {
+          let value = datastore.get_live(&query.key.as_str().into(), now);
+          OperationResult::Read(value.map(|value| ReadResponse::from(&*value)))
+        }
+        TransactionOp::Put(query) => {
+          changes.replaced(query.key.as_str().into(), current_value(&datastore, &query.key));
+          query.apply(&datastore, version, now);
+          OperationResult::Put { version }
+        }
+        TransactionOp::Delete(query) => {
+          changes.replaced(query.key.as_str().into(), current_value(&datastore, &query.key));
+          query.apply(&datastore);
+          OperationResult::Delete
+        }
+        TransactionOp::Check { .. } => OperationResult::Check,
+      })
+      .collect();
+
+    bincode::serialize(&TransactionResponse { results })
+      .map_err(|err| QueryError::Internal(err.to_string()))
+  }
+}
+
+/// The value of `key`, expired or not.
+fn current_value(datastore: &DataStore, key: &str) -> Option<DataStoreValue> {
+  datastore.map.get(&DataStoreKey::from(key)).map(|value| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn put(key: &str, value: &[u8], if_version: Option<u64>) -> TransactionOp {
+    let query = PutQuery { key: key.to_string(), value: value.to_vec(), expiry: None, if_version };
+    TransactionOp::Put(query)
+  }
+
+  fn read(key: &str) -> TransactionOp {
+    TransactionOp::Read(ReadQuery { key: key.to_string() })
+  }
+
+  fn exec(
+    store: &DataStore,
+    operations: Vec<TransactionOp>,
+  ) -> (Result<Vec<u8>, QueryError>, Changes) {
+    let mut changes = Changes::default();
+    let result = TransactionQuery { operations }.exec(store.clone(), 0, &mut changes);
+    (result, changes)
+  }
+
+  #[test]
+  fn writes_nothing_if_a_condition_fails() {
+    let store = DataStore::default();
+    exec(&store, vec![put("a", b"1", None)]).0.unwrap();
+
+    let failing = [
+      TransactionOp::Check { key: "a".to_string(), version: 2 },
+      put("a", b"2", Some(2)),
+      TransactionOp::Delete(DeleteQuery { key: "a".to_string(), if_version: Some(0) }),
+    ];
+    for condition in failing {
+      let (result, changes) = exec(&store, vec![put("b", b"1", None), condition]);
+      assert!(matches!(result, Err(QueryError::ConditionFailed)));
+      assert!(changes.is_empty());
+      assert!(store.get_live(&"b".into(), 0).is_none());
+      assert_eq!(store.version(), 1);
+    }
+  }
+
+  #[test]
+  fn reads_see_earlier_writes() {
+    let store = DataStore::default();
+    let (result, _) = exec(&store, vec![read("a"), put("a", b"1", None), read("a")]);
+    let response: TransactionResponse = bincode::deserialize(&result.unwrap()).unwrap();
+
+    match response.results.as_slice() {
+      [OperationResult::Read(None), OperationResult::Put { version: 1 }, OperationResult::Read(Some(read))] =>
+      {
+        assert_eq!(read.value, b"1");
+        assert_eq!(read.version, 1);
+      }
+      results => panic!("Unexpected results {results:?}"),
+    }
+  }
+
+  #[test]
+  fn writes_share_one_version_and_one_log() {
+    let store = DataStore::default();
+    exec(&store, vec![put("c", b"1", None)]).0.unwrap();
+
+    let delete = TransactionOp::Delete(DeleteQuery { key: "c".to_string(), if_version: None });
+    let (result, changes) = exec(&store, vec![put("a", b"1", None), put("b", b"1", None), delete]);
+    result.unwrap();
+
+    assert_eq!(store.version(), 2);
+    assert_eq!(store.get_live(&"a".into(), 0).unwrap().version, 2);
+    assert_eq!(store.get_live(&"b".into(), 0).unwrap().version, 2);
+    assert!(store.get_live(&"c".into(), 0).is_none());
+
+    let logs = changes.into_logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].version, 2);
+  }
+}
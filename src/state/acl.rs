@@ -0,0 +1,178 @@
+use std::{
+  collections::HashMap,
+  fs, io,
+  path::{Path, PathBuf},
+  sync::LazyLock,
+};
+
+use argon2::{
+  password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString,
+  },
+  Argon2,
+};
+use serde::Deserialize;
+
+use crate::public_api::{
+  dataquery::{DataQuery, QueryError},
+  transaction::TransactionOp,
+};
+
+/// Who is allowed to do what, loaded from a TOML file like:
+///
+/// ```toml
+/// [[users]]
+/// name = "billing"
+/// # Argon2 PHC string, e.g. from `argon2` on the command line.
+/// password_hash = "$argon2id$v=19$m=19456,t=2,p=1$..."
+/// # Names of [crate::tcp::server::CommandV0]s, or "*" for all of them.
+/// commands = ["Get", "MultiGet", "Scan"]
+/// # Keys the user may touch. "" allows every key.
+/// key_prefixes = ["billing/"]
+/// ```
+pub struct Acl {
+  path: PathBuf,
+  users: HashMap<String, User>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AclFile {
+  users: Vec<User>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct User {
+  pub name: String,
+  password_hash: String,
+  #[serde(default)]
+  pub commands: Vec<String>,
+  #[serde(default)]
+  pub key_prefixes: Vec<String>,
+}
+
+/// Checked instead of a user's hash when the user doesn't exist.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+  let salt = SaltString::generate(&mut OsRng);
+  Argon2::default()
+    .hash_password(b"memory-db", &salt)
+    .expect("Hashing with the default parameters works")
+    .to_string()
+});
+
+/// The user a connection authenticated as.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+  pub user: Option<String>,
+}
+
+impl Acl {
+  pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    let file: AclFile = toml::from_str(&fs::read_to_string(&path)?)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    for user in &file.users {
+      PasswordHash::new(&user.password_hash).map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("User {}: {}", user.name, err))
+      })?;
+    }
+
+    let users = file.users.into_iter().map(|user| (user.name.clone(), user)).collect();
+    Ok(Acl { path, users })
+  }
+
+  /// Loads the file again, e.g. after it was edited.
+  pub fn reload(&self) -> io::Result<Self> {
+    Acl::load(&self.path)
+  }
+
+  /// Slow on purpose, since it hashes the password. Takes as long for unknown users, so the
+  /// time doesn't tell which users exist.
+  pub fn verify_password(&self, user: &str, password: &str) -> bool {
+    let (password_hash, known) = match self.users.get(user) {
+      Some(user) => (user.password_hash.as_str(), true),
+      None => (DUMMY_HASH.as_str(), false),
+    };
+    let Ok(hash) = PasswordHash::new(password_hash) else {
+      return false;
+    };
+
+    Argon2::default().verify_password(password.as_bytes(), &hash).is_ok() && known
+  }
+
+  /// Whether the user of `session` may run `query`.
+  pub fn check(&self, session: &Session, query: &DataQuery) -> Result<(), QueryError> {
+    // Users that were removed by a reload lose their access right away.
+    let Some(user) = session.user.as_ref().and_then(|user| self.users.get(user)) else {
+      return Err(QueryError::Unauthenticated);
+    };
+
+    // The operations of a transaction need the same permission as on their own, so it can't be
+    // used to write with read-only access.
+    let mut commands = vec![query.command()];
+    if let DataQuery::Transaction(transaction) = query {
+      commands.extend(transaction.operations.iter().map(TransactionOp::command));
+    }
+    for command in commands {
+      let command = format!("{:?}", command);
+      if !user.commands.iter().any(|allowed| allowed == "*" || *allowed == command) {
+        return Err(QueryError::Forbidden(format!("{} may not run {}", user.name, command)));
+      }
+    }
+
+    for key in query.keys() {
+      if !user.key_prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+        return Err(QueryError::Forbidden(format!("{} may not access {:?}", user.name, key)));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::public_api::{
+    dataquery::{DeleteQuery, PutQuery, ReadQuery},
+    transaction::TransactionQuery,
+  };
+
+  #[test]
+  fn verifies_passwords() {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default().hash_password(b"secret", &salt).unwrap().to_string();
+    let user =
+      User { name: "a".to_string(), password_hash, commands: vec![], key_prefixes: vec![] };
+    let acl = Acl { path: PathBuf::new(), users: HashMap::from([("a".to_string(), user)]) };
+
+    assert!(acl.verify_password("a", "secret"));
+    assert!(!acl.verify_password("a", "wrong"));
+    assert!(!acl.verify_password("b", "secret"));
+    assert!(!acl.verify_password("b", "memory-db"));
+  }
+
+  #[test]
+  fn checks_each_operation_of_transactions() {
+    let user = User {
+      name: "reader".to_string(),
+      password_hash: String::new(),
+      commands: vec!["Get".to_string(), "Transaction".to_string()],
+      key_prefixes: vec![String::new()],
+    };
+    let acl = Acl { path: PathBuf::new(), users: HashMap::from([("reader".to_string(), user)]) };
+    let session = Session { user: Some("reader".to_string()) };
+    let transaction = |op| DataQuery::Transaction(TransactionQuery { operations: vec![op] });
+
+    let read = TransactionOp::Read(ReadQuery { key: "k".to_string() });
+    assert!(acl.check(&session, &transaction(read)).is_ok());
+    let check = TransactionOp::Check { key: "k".to_string(), version: 0 };
+    assert!(acl.check(&session, &transaction(check)).is_ok());
+
+    let put = PutQuery { key: "k".to_string(), value: vec![], expiry: None, if_version: None };
+    let delete = DeleteQuery { key: "k".to_string(), if_version: None };
+    for op in [TransactionOp::Put(put), TransactionOp::Delete(delete)] {
+      assert!(matches!(acl.check(&session, &transaction(op)), Err(QueryError::Forbidden(_))));
+    }
+  }
+}
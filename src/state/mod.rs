@@ -1,21 +1,12 @@
+mod acl;
+pub use acl::*;
+mod node_info;
+pub use node_info::*;
 mod node_state;
 pub use node_state::*;
-mod utils;
-
-#[cfg(debug_assertions)]
-const SNAPSHOT_DIR: &str = "./memorydb/snapshots";
-#[cfg(not(debug_assertions))]
-const SNAPSHOT_DIR: &str = "/etc/memorydb/snapshots";
-
-#[cfg(debug_assertions)]
-const WAL_FILE: &str = "./memorydb/data.wal";
-#[cfg(not(debug_assertions))]
-const WAL_FILE: &str = "/etc/memorydb/data.wal";
+mod snapshot;
+pub(crate) mod utils;
 
 const DATE_FMT: &str = "%Y-%m-%d-%H:%M:%S";
-const SNAPSHOT_KEEP_AMOUNT: usize = 10;
 
-#[cfg(debug_assertions)]
-const SNAPSHOT_WRITE_INTERVAL_SEC: u64 = 5;
-#[cfg(not(debug_assertions))]
-const SNAPSHOT_WRITE_INTERVAL_SEC: u64 = 60;
+const EXPIRED_KEYS_REAP_INTERVAL_MS: u64 = 1000;
@@ -0,0 +1,44 @@
+use std::{
+  sync::{PoisonError, RwLock},
+  time::Instant,
+};
+
+use crate::public_api::ping::{PingResponse, RaftRole};
+
+/// Facts about the running node that aren't part of the stored data.
+pub struct NodeInfo {
+  pub id: u64,
+  pub started_at: Instant,
+  raft_role: RwLock<Option<RaftRole>>,
+}
+
+impl Default for NodeInfo {
+  fn default() -> Self {
+    NodeInfo::new(NodeInfo::random_id())
+  }
+}
+
+impl NodeInfo {
+  pub fn new(id: u64) -> Self {
+    NodeInfo { id, started_at: Instant::now(), raft_role: RwLock::new(None) }
+  }
+
+  /// A random id for a new node. Never 0, which Raft doesn't accept.
+  pub fn random_id() -> u64 {
+    rand::random::<u64>().max(1)
+  }
+
+  /// Updated by the Raft state machine whenever the role of this node changes.
+  pub fn set_raft_role(&self, role: Option<RaftRole>) {
+    *self.raft_role.write().unwrap_or_else(PoisonError::into_inner) = role;
+  }
+
+  pub fn ping(&self) -> PingResponse {
+    PingResponse {
+      version: env!("CARGO_PKG_VERSION").to_string(),
+      node_id: self.id,
+      uptime_ms: self.started_at.elapsed().as_millis() as u64,
+      raft_role: *self.raft_role.read().unwrap_or_else(PoisonError::into_inner),
+    }
+  }
+}
@@ -1,96 +1,226 @@
 use std::{
-  collections::HashMap,
   fs::{self, File, OpenOptions},
-  io::{Read as _, Write as _},
-  path::Path,
-  sync::Arc,
+  sync::{Arc, Mutex, PoisonError, RwLock},
   time::Duration,
 };
 
-use super::utils;
+use super::{snapshot, utils, Acl, NodeInfo, Session};
 use chrono::Utc;
-use dashmap::DashMap;
-use tokio::{task, time::interval};
+use tokio::{
+  sync::Semaphore,
+  task::{self, AbortHandle},
+  time::interval,
+};
 use tracing::Level;
 
 use crate::{
-  log::DataChangeLog,
-  prelude::{DataStore, DataStoreKey, DataStoreValue},
-  public_api::dataquery::{DataQuery, HandleQuery as _},
+  config::{StorageConfig, WalFsync},
+  log::{
+    wal::{self, Wal, WalWriter},
+    Changes,
+  },
+  prelude::{DataStore, DataStoreSnapshot},
+  public_api::{
+    auth::AuthQuery,
+    dataquery::{DataQuery, HandleQuery as _, QueryError},
+  },
 };
 
-// Clone: Both fields are behind Arcs.
-#[derive(Clone, Default)]
+// Clone: All fields are behind Arcs.
+#[derive(Clone)]
 pub struct State {
   pub store: DataStore,
+  pub node: Arc<NodeInfo>,
+  /// Taken exclusively while a mutating query is logged and executed, and shared by read-only
+  /// queries, so they never see half of a transaction.
+  queries: Arc<RwLock<()>>,
+  /// Without one, every connection may run every query.
+  acl: Option<Arc<RwLock<Acl>>>,
+  /// One permit per password check that may run at a time, so a flood of Auth requests can't
+  /// take up every blocking thread.
+  auth_slots: Arc<Semaphore>,
+  /// Set with [State::with_node_id], otherwise the one stored in the data dir is used.
+  node_id: Option<u64>,
+  config: Arc<StorageConfig>,
+  wal: Arc<Mutex<Wal>>,
+  /// Set by [State::init].
+  running: Option<Arc<Running>>,
+}
+
+/// What an initialized [State] holds on to. Released once the last clone of the [State] is
+/// dropped, so instances can come and go within one process.
+struct Running {
+  /// Locked, so no other instance uses the same data dir.
+  _lock: File,
+  /// Started once the store is loaded, since it undoes failed writes in it.
+  wal_writer: WalWriter,
+  tasks: Vec<AbortHandle>,
+}
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+  }
 }
 
 impl State {
-  /// [State::init] does in order:
-  /// - Loads the newest snapshot, if one exists, it can find into memory.
-  /// - Reads the WAL and replays the data mutations to the snapshot
-  ///   (or empty data).
-  /// - Spawns thread for writing snapshots.
+  /// Call [State::init] before using it. Each instance keeps its data in its own
+  /// [StorageConfig::data_dir].
+  pub fn new(config: StorageConfig) -> Self {
+    let wal = Arc::new(Mutex::new(Wal::new(config.wal_dir())));
+
+    State {
+      store: DataStore::default(),
+      node: Arc::default(),
+      queries: Arc::default(),
+      acl: None,
+      auth_slots: Arc::new(Semaphore::new(
+        std::thread::available_parallelism().map_or(1, |parallelism| parallelism.get()),
+      )),
+      node_id: None,
+      wal,
+      config: Arc::new(config),
+      running: None,
+    }
+  }
 
-  fn install_snapshots(&mut self) -> std::io::Result<()> {
-    fs::create_dir_all(super::SNAPSHOT_DIR).expect("Cannot create database dirs");
-    if Path::new(super::SNAPSHOT_DIR).exists() {
-      let mut files = utils::files_in_dir(super::SNAPSHOT_DIR).unwrap();
+  /// Returns the first WAL segment to replay on top of the snapshot.
+  fn install_snapshots(&mut self) -> std::io::Result<u64> {
+    let snapshot_dir = self.config.snapshot_dir();
+    fs::create_dir_all(&snapshot_dir)?;
+    if snapshot_dir.exists() {
+      let mut files = utils::files_in_dir(&snapshot_dir)?;
+
+      utils::sort_snapshot_files(&mut files);
+
+      for file in files.iter().rev() {
+        let path = file.path();
+        tracing::trace!("Loading snapshot into memory: {:?}", path);
+
+        match fs::read(&path).ok().and_then(|snapshot_buf| snapshot::decode(&snapshot_buf)) {
+          Some((deserialized_snapshot, wal_segment)) => {
+            self.store = deserialized_snapshot.into();
+            return Ok(wal_segment);
+          }
+          // The file is left alone. The WAL segments after the older snapshot are still there.
+          None => tracing::error!("Corrupted snapshot, falling back to an older one: {:?}", path),
+        }
+      }
 
+      // The WAL segments before the oldest snapshot are gone, so starting empty would lose them.
       if !files.is_empty() {
-        utils::sort_snapshot_files(&mut files);
+        return Err(std::io::Error::new(
+          std::io::ErrorKind::InvalidData,
+          format!("None of the snapshots in {:?} can be loaded", snapshot_dir),
+        ));
+      }
+    };
+    Ok(0)
+  }
 
-        let newest_snapshot = files[files.len() - 1].path();
-        tracing::trace!("Loading snapshot into memory: {:?}", newest_snapshot);
+  fn replay_wal(&mut self, from_segment: u64) -> std::io::Result<()> {
+    let wal_dir = self.config.wal_dir();
 
-        let mut snapshot_file = File::open(newest_snapshot.clone())?;
+    // Before segments, the WAL was a single file. It becomes the first segment.
+    let single_file = self.config.data_dir.join("data.wal");
+    if single_file.exists() && wal::segments(&wal_dir)?.is_empty() {
+      fs::create_dir_all(&wal_dir)?;
+      fs::rename(&single_file, wal::segment_path(&wal_dir, 0))?;
+    }
 
-        let mut snapshot_buf = Vec::new();
-        snapshot_file.read_to_end(&mut snapshot_buf)?;
+    let segments = wal::segments(&wal_dir)?;
+    if let Some(first) = segments.first().filter(|first| **first > from_segment) {
+      // Starting without them would silently lose their changes.
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("WAL segments {from_segment} to {} are missing", first - 1),
+      ));
+    }
 
-        if let Ok(deserialized_snapshot) =
-          bincode::deserialize::<HashMap<DataStoreKey, DataStoreValue>>(&snapshot_buf)
-        {
-          let hash_map: DashMap<DataStoreKey, DataStoreValue> =
-            deserialized_snapshot.into_iter().collect();
+    let now = Utc::now().timestamp_millis();
+    for segment in segments.into_iter().filter(|segment| *segment >= from_segment) {
+      let data_mutate_logs = wal::recover(wal::segment_path(&wal_dir, segment))?;
 
-          self.store = DataStore(Arc::new(hash_map));
-        } else {
-          tracing::error!("Corrupted snapshot: {:?}", &newest_snapshot);
-          fs::remove_file(newest_snapshot).expect("Failed to remove corrupted snapshot");
-        };
+      for log in data_mutate_logs {
+        // The snapshot already contains this change.
+        if log.version <= self.store.version() {
+          continue;
+        }
+
+        tracing::trace!("Applying log: {:?}", &log);
+        log.replay(&self.store, now);
       }
-    };
+    }
+
     Ok(())
   }
 
-  fn replay_wal(&mut self) -> std::io::Result<()> {
-    if Path::new(super::WAL_FILE).exists() {
-      let data_mutate_logs: Vec<DataChangeLog> =
-        utils::read_appended_structs_from_file(super::WAL_FILE)?;
-
-      let dataquery_log: Vec<DataQuery> =
-        data_mutate_logs.iter().map(|x| x.clone().query.into()).collect();
-
-      for item in dataquery_log {
-        tracing::trace!("Applying log: {:?}", &item);
-        item.exec(self.store.clone());
+  async fn create_snapshot(
+    store: DataStore,
+    config: Arc<StorageConfig>,
+    queries: &RwLock<()>,
+    wal_writer: &WalWriter,
+    wal: Arc<Mutex<Wal>>,
+  ) -> std::io::Result<()> {
+    // No write can sneak in between, so every change logged before the new segment is in the
+    // store at `version`.
+    let permit = match wal_writer.reserve().await {
+      Ok(permit) => permit,
+      Err(err) => {
+        tracing::error!("WAL rotation error: {:?}", err);
+        return Ok(());
+      }
+    };
+    let (version, rotated) = {
+      let _write = queries.write().unwrap_or_else(PoisonError::into_inner);
+      (store.version(), permit.rotate())
+    };
+    let wal_segment = match rotated.await {
+      Ok(wal_segment) => wal_segment,
+      Err(err) => {
+        tracing::error!("WAL rotation error: {:?}", err);
+        return Ok(());
       }
+    };
+
+    // Copying, encoding and writing a large store takes a while, so it must not hold up a worker.
+    let mut data =
+      task::spawn_blocking(move || store.snapshot()).await.map_err(std::io::Error::other)?;
+    // The data may already contain some later changes. Replaying them again does no harm.
+    data.version = version;
+
+    // The copy is taken without the lock, so it may contain changes whose WAL write fails later
+    // and gets undone. Once no query holds the lock, all of them are queued, and once the sync
+    // behind them is done, a failure among them has been flagged.
+    drop(queries.read().unwrap_or_else(PoisonError::into_inner));
+    if let Err(err) = wal_writer.sync().await {
+      tracing::error!("WAL sync error, skipping the snapshot: {:?}", err);
+      return Ok(());
+    }
+    if wal_writer.failed() {
+      tracing::error!("Skipping the snapshot, it may contain changes the WAL failed to write");
+      return Ok(());
     }
 
-    Ok(())
+    task::spawn_blocking(move || State::write_snapshot(&data, &config, &wal, wal_segment))
+      .await
+      .map_err(std::io::Error::other)?
   }
 
-  fn create_snapshot(store: DataStore) -> std::io::Result<()> {
+  fn write_snapshot(
+    data: &DataStoreSnapshot,
+    config: &StorageConfig,
+    wal: &Mutex<Wal>,
+    wal_segment: u64,
+  ) -> std::io::Result<()> {
     let _span = tracing::span!(Level::TRACE, "Snapshot");
     let _span = _span.enter();
 
     tracing::trace!("Starting snapshot");
 
-    let hash_map: HashMap<DataStoreKey, DataStoreValue> =
-      store.0.iter().map(|e| (e.key().clone(), e.value().clone())).collect();
-
-    let snapshot_rawdata = match bincode::serialize(&hash_map) {
+    let snapshot_rawdata = match snapshot::encode(data, wal_segment) {
       Ok(data) => data,
       Err(err) => {
         tracing::error!("Binary serialization error: {:?}", err);
@@ -104,82 +234,505 @@ impl State {
 
     let file_name = format!("{formatted_date}-memorydb.dat");
 
-    if let Err(err) = fs::create_dir_all(super::SNAPSHOT_DIR) {
+    let snapshot_dir = config.snapshot_dir();
+    if let Err(err) = fs::create_dir_all(&snapshot_dir) {
       tracing::error!("File system access error: {:?}", err);
 
       // TODO
       return Ok(());
     };
 
-    let mut file = OpenOptions::new()
-      .write(true)
-      .create(true)
-      .open(format!("{}/{file_name}", super::SNAPSHOT_DIR))
-      .unwrap();
-
-    if let Err(err) = file.write_all(&snapshot_rawdata) {
+    let tmp_path = config.data_dir.join("snapshot.tmp");
+    if let Err(err) =
+      utils::write_file_atomic(snapshot_dir.join(file_name), tmp_path, &snapshot_rawdata)
+    {
       tracing::error!("File write error: {:?}", err);
       // TODO
       return Ok(());
     };
 
-    if let Err(err) = file.flush() {
-      tracing::error!("File flush error: {:?}", err);
-      // TODO
-      return Ok(());
-    };
-
     tracing::trace!("Success");
 
-    let file_options =
-      OpenOptions::new().write(true).truncate(true).open(Path::new(super::WAL_FILE));
-
-    if let Ok(file) = file_options {
-      file.set_len(0).unwrap();
-    };
-
     tracing::trace!("Cleaning old snapshots");
-    let mut files = utils::files_in_dir(super::SNAPSHOT_DIR).unwrap();
+    let mut files = utils::files_in_dir(&snapshot_dir)?;
     utils::sort_snapshot_files(&mut files);
 
-    if files.len() > super::SNAPSHOT_KEEP_AMOUNT {
-      let files_to_remove = files.len() - super::SNAPSHOT_KEEP_AMOUNT;
-
-      for item in files.iter().take(files_to_remove) {
-        if let Err(err) = std::fs::remove_file(item.path()) {
+    // Only snapshots that can be loaded count, so corrupted ones can't push them out.
+    let (mut snapshots, corrupted): (Vec<_>, Vec<_>) = files
+      .iter()
+      .enumerate()
+      .map(|(index, file)| {
+        let path = file.path();
+        let wal_segment = fs::read(&path).ok().and_then(|buf| snapshot::wal_segment(&buf));
+        (index, path, wal_segment)
+      })
+      .partition(|(_, _, wal_segment)| wal_segment.is_some());
+
+    if snapshots.len() > config.snapshot_keep {
+      let files_to_remove = snapshots.len() - config.snapshot_keep;
+
+      for (_, path, _) in snapshots.drain(..files_to_remove) {
+        if let Err(err) = std::fs::remove_file(path) {
           tracing::error!("Old snapshot delete error: {:?}", err);
           continue;
         };
       }
     }
+
+    // Corrupted snapshots older than every kept one would never be loaded again.
+    let oldest_kept = snapshots.first().map_or(0, |(index, _, _)| *index);
+    for (_, path, _) in corrupted.iter().filter(|(index, _, _)| *index < oldest_kept) {
+      tracing::warn!("Deleting corrupted snapshot {:?}", path);
+      if let Err(err) = std::fs::remove_file(path) {
+        tracing::error!("Old snapshot delete error: {:?}", err);
+      };
+    }
+
+    // The older snapshots are the fallback if a newer one turns out corrupted, so the segments
+    // after the oldest one are kept.
+    let needed = snapshots.first().and_then(|(_, _, wal_segment)| *wal_segment);
+    let needed = needed.unwrap_or(wal_segment);
+    let removed = wal.lock().unwrap_or_else(PoisonError::into_inner).remove_segments_before(needed);
+    if let Err(err) = removed {
+      tracing::error!("Old WAL segment delete error: {:?}", err);
+    };
+    Ok(())
+  }
+  /// Requires connections to authenticate, and limits what each user can do.
+  pub fn with_acl(mut self, acl: Acl) -> Self {
+    self.acl = Some(Arc::new(RwLock::new(acl)));
+    self
+  }
+
+  /// Uses `node_id` instead of the id stored in the data dir. Must not be 0.
+  pub fn with_node_id(mut self, node_id: u64) -> Self {
+    self.node_id = Some(node_id);
+    self
+  }
+
+  /// The id stored in the data dir. A new node picks a random one and stores it, so it keeps
+  /// its id across restarts.
+  fn load_node_id(&self) -> std::io::Result<u64> {
+    let path = self.config.data_dir.join("NODE_ID");
+    match fs::read_to_string(&path) {
+      Ok(id) => id.trim().parse().map_err(|err| {
+        std::io::Error::new(
+          std::io::ErrorKind::InvalidData,
+          format!("Invalid node id in {:?}: {}", path, err),
+        )
+      }),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        let id = NodeInfo::random_id();
+        let tmp_path = self.config.data_dir.join("NODE_ID.tmp");
+        utils::write_file_atomic(&path, tmp_path, id.to_string().as_bytes())?;
+        Ok(id)
+      }
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Loads the ACL file again. If it can't be loaded, the current ACL stays in place.
+  pub fn reload_acl(&self) -> std::io::Result<()> {
+    match &self.acl {
+      Some(acl) => State::reload(acl),
+      None => Ok(()),
+    }
+  }
+
+  fn reload(acl: &RwLock<Acl>) -> std::io::Result<()> {
+    let reloaded = acl.read().unwrap_or_else(PoisonError::into_inner).reload()?;
+    *acl.write().unwrap_or_else(PoisonError::into_inner) = reloaded;
+    tracing::info!("Reloaded ACL");
     Ok(())
   }
+
+  /// The session of a connection after it sent `query`. Fails if the password is wrong.
+  pub async fn authenticate(&self, query: AuthQuery) -> Result<Session, QueryError> {
+    let Some(acl) = self.acl.clone() else {
+      return Ok(Session { user: Some(query.user) });
+    };
+
+    // Verifying the password hash takes a while, so keep it off the async workers.
+    let permit =
+      self.auth_slots.clone().acquire_owned().await.expect("The semaphore is never closed");
+    task::spawn_blocking(move || {
+      let _permit = permit;
+      let acl = acl.read().unwrap_or_else(PoisonError::into_inner);
+      if acl.verify_password(&query.user, &query.password) {
+        Ok(Session { user: Some(query.user) })
+      } else {
+        Err(QueryError::Unauthenticated)
+      }
+    })
+    .await
+    .map_err(|err| QueryError::Internal(err.to_string()))?
+  }
+
+  /// [State::init] does in order:
+  /// - Locks the data dir, it fails if another instance uses it.
+  /// - Loads the node id from the data dir, unless one was set.
+  /// - Loads the newest snapshot, if one exists, it can find into memory.
+  /// - Reads the WAL and replays the data mutations to the snapshot
+  ///   (or empty data).
+  /// - Starts the WAL writer thread.
+  /// - Spawns thread for writing snapshots.
+  /// - Spawns thread for removing expired keys.
+  /// - With an ACL, spawns a task that reloads it on SIGHUP.
+  ///
+  /// The tasks stop when the last clone of the [State] is dropped.
   pub fn init(&mut self) -> std::io::Result<()> {
-    self.install_snapshots();
-    self.replay_wal();
+    let lock = self.lock_data_dir()?;
+    let node_id = match self.node_id {
+      Some(node_id) => node_id,
+      None => self.load_node_id()?,
+    };
+    self.node = Arc::new(NodeInfo::new(node_id));
+    let wal_segment = self.install_snapshots()?;
+    self.replay_wal(wal_segment)?;
+
+    let sync = self.config.wal_fsync == WalFsync::Always;
+    let wal_writer =
+      WalWriter::spawn(self.wal.clone(), sync, self.store.clone(), self.queries.clone())?;
+
+    // The tasks only hold what they need, not the whole state, or it would never be dropped.
+    let mut tasks = Vec::new();
 
     let store = self.store.clone();
-    task::spawn(async move {
-      let mut timing = interval(Duration::from_secs(super::SNAPSHOT_WRITE_INTERVAL_SEC));
+    let config = self.config.clone();
+    let queries = self.queries.clone();
+    let snapshot_wal_writer = wal_writer.clone();
+    let wal = self.wal.clone();
+    let snapshots = task::spawn(async move {
+      let mut timing = interval(Duration::from_secs(config.snapshot_interval_sec));
 
       // First tick completes immediately
       timing.tick().await;
 
       loop {
         timing.tick().await;
-        State::create_snapshot(store.clone());
+        if let Err(err) = State::create_snapshot(
+          store.clone(),
+          config.clone(),
+          &queries,
+          &snapshot_wal_writer,
+          wal.clone(),
+        )
+        .await
+        {
+          tracing::error!("Snapshot error: {:?}", err);
+        }
+      }
+    });
+    tasks.push(snapshots.abort_handle());
+
+    let store = self.store.clone();
+    let reaper = task::spawn(async move {
+      let mut timing = interval(Duration::from_millis(super::EXPIRED_KEYS_REAP_INTERVAL_MS));
+
+      loop {
+        timing.tick().await;
+        State::reap_expired(&store);
       }
     });
+    tasks.push(reaper.abort_handle());
+
+    if self.config.wal_fsync == WalFsync::Interval {
+      // Syncing blocks, so it's left to the WAL writer thread.
+      let wal_writer = wal_writer.clone();
+      let period = Duration::from_millis(self.config.wal_fsync_interval_ms);
+      let syncer = task::spawn(async move {
+        let mut timing = interval(period);
+
+        loop {
+          timing.tick().await;
+          if let Err(err) = wal_writer.sync().await {
+            tracing::error!("WAL sync error: {:?}", err);
+          }
+        }
+      });
+      tasks.push(syncer.abort_handle());
+    }
+
+    #[cfg(unix)]
+    if let Some(acl) = self.acl.clone() {
+      let reloader = task::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+          tracing::error!("Could not listen for SIGHUP, the ACL can't be reloaded");
+          return;
+        };
+        while hangups.recv().await.is_some() {
+          if let Err(err) = State::reload(&acl) {
+            tracing::error!("Failed to reload ACL: {:?}", err);
+          }
+        }
+      });
+      tasks.push(reloader.abort_handle());
+    }
 
+    self.running = Some(Arc::new(Running { _lock: lock, wal_writer, tasks }));
     Ok(())
   }
 
-  pub async fn handle_query(&mut self, query: DataQuery) -> Vec<u8> {
-    if let Some(logs) = query.as_datachangelogs() {
-      for log in logs {
-        utils::append_struct_to_file(super::WAL_FILE, &log).unwrap();
+  fn lock_data_dir(&self) -> std::io::Result<File> {
+    fs::create_dir_all(&self.config.data_dir)?;
+    let lock = OpenOptions::new()
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(self.config.data_dir.join("LOCK"))?;
+    lock.try_lock().map_err(|_| {
+      std::io::Error::new(
+        std::io::ErrorKind::WouldBlock,
+        format!("Data dir {:?} is used by another instance", self.config.data_dir),
+      )
+    })?;
+    Ok(lock)
+  }
+
+  /// Removes expired values, so they stop taking up memory. Reads already treat them as missing,
+  /// so this doesn't need to be logged.
+  fn reap_expired(store: &DataStore) {
+    store.remove_expired(Utc::now().timestamp_millis());
+  }
+
+  pub async fn handle_query(
+    &mut self,
+    mut query: DataQuery,
+    session: &Session,
+  ) -> Result<Vec<u8>, QueryError> {
+    if let Some(acl) = &self.acl {
+      acl.read().unwrap_or_else(PoisonError::into_inner).check(session, &query)?;
+    }
+
+    // Expiries and conditions are all checked against this one point in time, so the WAL and the
+    // store agree on them.
+    let now = Utc::now().timestamp_millis();
+    query.pin_expiry(now);
+
+    // Room in the WAL queue is taken before the lock, so a full queue doesn't hold up reads.
+    let wal = if query.is_read_only() {
+      None
+    } else {
+      let Some(running) = &self.running else {
+        return Err(QueryError::Internal("The state is not initialized".to_string()));
+      };
+      let permit = running.wal_writer.reserve().await;
+      let permit = permit.map_err(|err| QueryError::Internal(err.to_string()))?;
+      Some((&running.wal_writer, permit))
+    };
+
+    // Writes are serialized, so the WAL gets them in the order they are applied, and conditional
+    // writes can't be raced between checking their condition and executing. Waiting for the WAL
+    // happens after the lock is released, so concurrent writes share a batch.
+    let (written, result) = {
+      let (_read, _write) = if query.is_read_only() {
+        (Some(self.queries.read().unwrap_or_else(PoisonError::into_inner)), None)
+      } else {
+        (None, Some(self.queries.write().unwrap_or_else(PoisonError::into_inner)))
+      };
+
+      if wal.as_ref().is_some_and(|(wal_writer, _)| wal_writer.failed()) {
+        return Err(QueryError::Internal(
+          "Writes are refused after a failed WAL write".to_string(),
+        ));
       }
+
+      // The logs are what the query actually did, so the WAL can't disagree with the store.
+      let mut changes = Changes::default();
+      let result = query.exec(self.store.clone(), now, &mut changes);
+      let written = match wal {
+        Some((_, permit)) if !changes.is_empty() => Some(permit.append(changes)),
+        _ => None,
+      };
+      (written, result)
+    };
+
+    // The change is visible before it is in the WAL, but it's only acknowledged after. If the
+    // write fails, the WAL writer undoes it.
+    if let Some(written) = written {
+      // The WAL writer already logged the error.
+      written.await.map_err(|_| QueryError::Internal("Failed to write to the WAL".to_string()))?;
     }
-    query.exec(self.store.clone())
+    result
+  }
+}
+
+impl Default for State {
+  fn default() -> Self {
+    State::new(StorageConfig::default())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::public_api::dataquery::{PutQuery, ReadQuery};
+
+  fn state(data_dir: &std::path::Path) -> State {
+    State::new(StorageConfig { data_dir: data_dir.to_path_buf(), ..StorageConfig::default() })
+  }
+
+  #[tokio::test]
+  async fn undoes_writes_the_wal_failed_on() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = state(dir.path());
+    state.init().unwrap();
+    // The segment can't be opened for appending.
+    fs::create_dir_all(wal::segment_path(state.config.wal_dir(), 0)).unwrap();
+
+    let session = Session::default();
+    let put = |key: &str| {
+      let put = PutQuery { key: key.to_string(), value: vec![], expiry: None, if_version: None };
+      DataQuery::Put(put)
+    };
+    assert!(state.handle_query(put("k"), &session).await.is_err());
+    let read = DataQuery::Read(ReadQuery { key: "k".to_string() });
+    assert!(matches!(state.handle_query(read, &session).await, Err(QueryError::NotFound)));
+    // The store could get ahead of the WAL otherwise, so writes stay refused.
+    assert!(state.handle_query(put("other"), &session).await.is_err());
+    assert!(state.store.map.is_empty());
+  }
+
+  #[tokio::test]
+  async fn skips_snapshots_after_failed_wal_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = state(dir.path());
+    state.init().unwrap();
+    fs::create_dir_all(wal::segment_path(state.config.wal_dir(), 0)).unwrap();
+    let put = PutQuery { key: "k".to_string(), value: vec![], expiry: None, if_version: None };
+    assert!(state.handle_query(DataQuery::Put(put), &Session::default()).await.is_err());
+
+    let wal_writer = &state.running.as_ref().unwrap().wal_writer;
+    State::create_snapshot(
+      state.store.clone(),
+      state.config.clone(),
+      &state.queries,
+      wal_writer,
+      state.wal.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(fs::read_dir(state.config.snapshot_dir()).unwrap().count(), 0);
+  }
+
+  #[tokio::test]
+  async fn fails_on_missing_wal_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = state(dir.path());
+    let wal_dir = state.config.wal_dir();
+    fs::create_dir_all(&wal_dir).unwrap();
+    fs::write(wal::segment_path(&wal_dir, 2), b"").unwrap();
+
+    assert!(state.init().is_err());
+  }
+
+  /// Writes a snapshot holding `key`, or garbage, named after `day` in January 2020.
+  fn write_snapshot(dir: &std::path::Path, day: u32, key: Option<&str>) -> std::path::PathBuf {
+    let buf = match key {
+      Some(key) => {
+        let value = crate::prelude::DataStoreValue::new(b"v".to_vec(), None, 1);
+        let data = [(key.into(), value)].into_iter().collect();
+        snapshot::encode(&crate::prelude::DataStoreSnapshot { version: 1, data }, 0).unwrap()
+      }
+      None => b"corrupted".to_vec(),
+    };
+    let dir = dir.join("snapshots");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("2020-01-{day:02}-00:00:00-memorydb.dat"));
+    fs::write(&path, buf).unwrap();
+    path
+  }
+
+  #[tokio::test]
+  async fn falls_back_to_older_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    write_snapshot(dir.path(), 1, Some("old"));
+    write_snapshot(dir.path(), 2, None);
+
+    let mut state = state(dir.path());
+    state.init().unwrap();
+    assert!(state.store.get_live(&"old".into(), 0).is_some());
+  }
+
+  #[tokio::test]
+  async fn fails_if_no_snapshot_loads() {
+    let dir = tempfile::tempdir().unwrap();
+    write_snapshot(dir.path(), 1, None);
+
+    assert!(state(dir.path()).init().is_err());
+  }
+
+  #[tokio::test]
+  async fn keeps_snapshots_that_load() {
+    let dir = tempfile::tempdir().unwrap();
+    let valid = write_snapshot(dir.path(), 1, Some("old"));
+    let corrupted = write_snapshot(dir.path(), 2, None);
+
+    let config = StorageConfig { snapshot_keep: 2, ..state(dir.path()).config.as_ref().clone() };
+    let mut state = State::new(config);
+    state.init().unwrap();
+    let wal_writer = &state.running.as_ref().unwrap().wal_writer;
+    State::create_snapshot(
+      state.store.clone(),
+      state.config.clone(),
+      &state.queries,
+      wal_writer,
+      state.wal.clone(),
+    )
+    .await
+    .unwrap();
+
+    // The new snapshot and the valid old one make two, the corrupted one doesn't count.
+    assert!(valid.exists() && corrupted.exists());
+    assert_eq!(fs::read_dir(dir.path().join("snapshots")).unwrap().count(), 3);
+  }
+
+  #[tokio::test]
+  async fn deletes_corrupted_snapshots_older_than_the_kept_ones() {
+    let dir = tempfile::tempdir().unwrap();
+    let corrupted = write_snapshot(dir.path(), 1, None);
+    let removed = write_snapshot(dir.path(), 2, Some("old"));
+    let kept = write_snapshot(dir.path(), 3, Some("new"));
+
+    let config = StorageConfig { snapshot_keep: 2, ..state(dir.path()).config.as_ref().clone() };
+    let mut state = State::new(config);
+    state.init().unwrap();
+    let wal_writer = &state.running.as_ref().unwrap().wal_writer;
+    State::create_snapshot(
+      state.store.clone(),
+      state.config.clone(),
+      &state.queries,
+      wal_writer,
+      state.wal.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert!(!corrupted.exists() && !removed.exists() && kept.exists());
+    assert_eq!(fs::read_dir(dir.path().join("snapshots")).unwrap().count(), 2);
+  }
+
+  #[tokio::test]
+  async fn instances_share_nothing() {
+    let (dir_a, dir_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let (mut a, mut b) = (state(dir_a.path()), state(dir_b.path()));
+    a.init().unwrap();
+    b.init().unwrap();
+
+    let put =
+      PutQuery { key: "k".to_string(), value: b"v".to_vec(), expiry: None, if_version: None };
+    a.handle_query(DataQuery::Put(put), &Session::default()).await.unwrap();
+    let read = || DataQuery::Read(ReadQuery { key: "k".to_string() });
+    assert!(a.handle_query(read(), &Session::default()).await.is_ok());
+    assert!(matches!(b.handle_query(read(), &Session::default()).await, Err(QueryError::NotFound)));
+
+    // Each holds the LOCK of its own data dir only.
+    assert!(dir_a.path().join("LOCK").exists() && dir_b.path().join("LOCK").exists());
+    assert!(state(dir_a.path()).init().is_err());
+    drop(b);
+    state(dir_b.path()).init().unwrap();
   }
 }
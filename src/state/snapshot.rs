@@ -0,0 +1,116 @@
+//! Snapshot files start with [MAGIC], [FORMAT_VERSION] and the number of the first WAL segment
+//! the snapshot doesn't cover, followed by the bincode encoded [DataStoreSnapshot] and a crc32c
+//! of everything before it.
+//!
+//! Snapshots from before format version 1 are a plain bincode map of keys to values.
+
+use std::collections::HashMap;
+
+use bincode::Options as _;
+
+use crate::prelude::{DataStoreKey, DataStoreSnapshot, DataStoreValue, UNVERSIONED_VERSION};
+
+const MAGIC: [u8; 4] = *b"MDBS";
+/// Bumped whenever the layout changes, so older files can be told apart. Version 1 had no
+/// checksum.
+const FORMAT_VERSION: u8 = 2;
+
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+const CHECKSUM_LEN: usize = 4;
+
+pub fn encode(snapshot: &DataStoreSnapshot, wal_segment: u64) -> bincode::Result<Vec<u8>> {
+  let mut buf = Vec::with_capacity(HEADER_LEN);
+  buf.extend(MAGIC);
+  buf.push(FORMAT_VERSION);
+  buf.extend(wal_segment.to_le_bytes());
+  bincode::serialize_into(&mut buf, snapshot)?;
+  buf.extend(crc32c::crc32c(&buf).to_le_bytes());
+  Ok(buf)
+}
+
+/// The snapshot and the first WAL segment to replay on top of it, or [None] if the file is
+/// corrupted. Snapshots written before format version 1 don't know their segment, so every
+/// segment is replayed.
+pub fn decode(buf: &[u8]) -> Option<(DataStoreSnapshot, u64)> {
+  if !buf.starts_with(&MAGIC) {
+    return decode_unversioned(buf).map(|snapshot| (snapshot, 0));
+  }
+  let (wal_segment, body) = split(buf)?;
+  let snapshot = bincode::deserialize(body).ok()?;
+  Some((snapshot, wal_segment))
+}
+
+/// Like [decode], but only checks the header and checksum instead of decoding the whole
+/// snapshot. Format version 1 has no checksum, so only its header is checked.
+pub fn wal_segment(buf: &[u8]) -> Option<u64> {
+  if !buf.starts_with(&MAGIC) {
+    return decode_unversioned(buf).map(|_| 0);
+  }
+  split(buf).map(|(wal_segment, _)| wal_segment)
+}
+
+/// The WAL segment and the encoded [DataStoreSnapshot] of a file starting with [MAGIC].
+fn split(buf: &[u8]) -> Option<(u64, &[u8])> {
+  let (&version, rest) = buf.strip_prefix(&MAGIC)?.split_first()?;
+  let rest = match version {
+    1 => rest,
+    FORMAT_VERSION => {
+      let (rest, checksum) = rest.split_at_checked(rest.len().checked_sub(CHECKSUM_LEN)?)?;
+      let covered = &buf[..buf.len() - CHECKSUM_LEN];
+      if crc32c::crc32c(covered).to_le_bytes() != checksum {
+        return None;
+      }
+      rest
+    }
+    _ => {
+      tracing::error!("Unsupported snapshot format version {version}");
+      return None;
+    }
+  };
+
+  let (wal_segment, body) = rest.split_at_checked(8)?;
+  Some((u64::from_le_bytes(wal_segment.try_into().ok()?), body))
+}
+
+/// Values back then were only bytes, without expiry or version.
+fn decode_unversioned(buf: &[u8]) -> Option<DataStoreSnapshot> {
+  let options = bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes();
+  let data: HashMap<DataStoreKey, Vec<u8>> = options.deserialize(buf).ok()?;
+  let data = data
+    .into_iter()
+    .map(|(key, value)| (key, DataStoreValue::new(value, None, UNVERSIONED_VERSION)))
+    .collect();
+
+  Some(DataStoreSnapshot { version: UNVERSIONED_VERSION, data })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_unversioned_snapshots() {
+    let data = HashMap::from([("key".to_string(), b"value".to_vec())]);
+    let buf = bincode::serialize(&data).unwrap();
+
+    let (snapshot, wal_segment) = decode(&buf).unwrap();
+    assert_eq!(wal_segment, 0);
+    assert_eq!(snapshot.version, UNVERSIONED_VERSION);
+    let value = &snapshot.data[&DataStoreKey::from("key")];
+    assert_eq!(&*value.data, b"value");
+    assert_eq!(value.expires_at, None);
+    assert_eq!(value.version, UNVERSIONED_VERSION);
+  }
+
+  #[test]
+  fn checks_the_checksum_for_the_wal_segment() {
+    let snapshot = DataStoreSnapshot { version: 1, data: Default::default() };
+    let mut buf = encode(&snapshot, 7).unwrap();
+    assert_eq!(wal_segment(&buf), Some(7));
+
+    let last = buf.len() - 1;
+    buf[last] ^= 1;
+    assert_eq!(wal_segment(&buf), None);
+    assert_eq!(wal_segment(&buf[..HEADER_LEN]), None);
+  }
+}
@@ -1,51 +1,14 @@
 use std::{
-  fs::{File, OpenOptions},
-  io::{self, Read as _, Write as _},
+  fs::{self, DirEntry, File},
+  io::{self, Write as _},
+  path::Path,
 };
 
-use serde::{de::DeserializeOwned, Serialize};
-
-pub fn read_appended_structs_from_file<T: DeserializeOwned + Serialize>(
-  file_path: &str,
-) -> io::Result<Vec<T>> {
-  let mut file = File::open(file_path)?;
-  let mut buffer = Vec::new();
-  file.read_to_end(&mut buffer)?;
-
-  let mut structs = Vec::new();
-  let mut offset = 0;
-
-  while offset < buffer.len() {
-    let next_struct: T = bincode::deserialize(&buffer[offset..])
-      .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize"))?;
-    structs.push(next_struct);
-
-    offset += (bincode::serialized_size(&structs.last().unwrap()).unwrap()) as usize;
-  }
-
-  Ok(structs)
-}
-
-pub fn append_struct_to_file<T: Serialize>(file_path: &str, value: &T) -> io::Result<()> {
-  let serialized_data = bincode::serialize(value)
-    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to serialize"))?;
-
-  let mut file = OpenOptions::new()
-    .create(true) // Create the file if it doesn't exist
-    .append(true) // Open in append mode
-    .open(file_path)?;
-
-  file.write_all(&serialized_data)?; // Write serialized data to the file
-  Ok(())
-}
-
-use std::fs::{self, DirEntry};
-
 use chrono::{DateTime, NaiveDateTime, Utc};
 
 use super::DATE_FMT;
 
-pub fn files_in_dir(dir_path: &str) -> Result<Vec<DirEntry>, std::io::Error> {
+pub fn files_in_dir(dir_path: impl AsRef<Path>) -> Result<Vec<DirEntry>, std::io::Error> {
   let entries = fs::read_dir(dir_path)?; // Read the directory
   let count = entries
     .filter_map(Result::ok) // Filter out errors
@@ -67,3 +30,25 @@ pub fn sort_snapshot_files(files: &mut [DirEntry]) {
     utc_datetime_a.cmp(&utc_datetime_b)
   });
 }
+
+/// Replaces the file at `path` with `data`, so that after a crash it holds either all of the old
+/// or all of the new content. `data` is written to `tmp_path` first, which has to be on the same
+/// file system.
+pub fn write_file_atomic(
+  path: impl AsRef<Path>,
+  tmp_path: impl AsRef<Path>,
+  data: &[u8],
+) -> io::Result<()> {
+  let (path, tmp_path) = (path.as_ref(), tmp_path.as_ref());
+
+  let mut file = File::create(tmp_path)?;
+  file.write_all(data)?;
+  file.sync_all()?;
+  fs::rename(tmp_path, path)?;
+
+  // The rename is only durable once the directory is synced.
+  if let Some(dir) = path.parent() {
+    File::open(dir)?.sync_all()?;
+  }
+  Ok(())
+}
@@ -9,7 +9,7 @@ use raft::{
   Config, RaftState, RawNode, Ready, Storage,
 };
 
-use crate::prelude::DataStore;
+use crate::{prelude::DataStore, public_api::ping::RaftRole};
 
 // TODO: Handle raft lifecycle and another thread and networking and whatnot wtf.
 pub struct RaftNode {
@@ -25,6 +25,11 @@ impl RaftNode {
     Ok(Self { node })
   }
 
+  /// Current role of this node in the cluster, reported by Ping.
+  pub fn role(&self) -> RaftRole {
+    self.node.raft.state.into()
+  }
+
   fn raft_tick(&mut self) -> Option<Ready> {
     if self.node.tick() {
       if self.node.has_ready() {
@@ -47,9 +52,7 @@ impl RaftNode {
     //
     // Check whether messages is empty or not. If not, it means that the node will send messages to other nodes:
     if !payload.messages().is_empty() {
-      for msg in payload.take_messages() {
-        unimplemented!("Send msgs to other peers.");
-      }
+      unimplemented!("Send msgs to other peers.");
     }
 
     // Step 2.
@@ -125,10 +128,8 @@ impl RaftNode {
     // If not, it means that the node will send messages to other nodes after persisting hardstate,
     // entries and snapshot
     if !payload.persisted_messages().is_empty() {
-      for msg in payload.take_persisted_messages() {
-        todo!("Todo what the fuck to do here")
-        // Send persisted messages to other peers.
-      }
+      todo!("Todo what the fuck to do here")
+      // Send persisted messages to other peers.
     }
 
     // Step 7.
@@ -136,7 +137,7 @@ impl RaftNode {
     // Call advance to notify that the previous work is completed.
     // Get the return value LightReady and handle its messages and committed_entries like step 1 and step 3 does.
     // Then call advance_apply to advance the applied index inside.
-    let light_rd = self.node.advance(payload);
+    let _light_rd = self.node.advance(payload);
 
     // WHAT THE FUCK
 
@@ -162,7 +163,7 @@ pub struct MyStorageCore {
 }
 
 impl MyStorageCore {
-  /// Example implementation: https://docs.rs/raft/latest/src/raft/storage.rs.html#243
+  // Example implementation: https://docs.rs/raft/latest/src/raft/storage.rs.html#243
 
   pub fn append(&mut self, entries: &[Entry]) -> raft::Result<()> {
     self.entries.extend_from_slice(entries);
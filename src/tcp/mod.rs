@@ -1,2 +1,5 @@
 pub mod protocol;
 pub mod server;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix_server;
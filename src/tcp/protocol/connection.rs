@@ -0,0 +1,107 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _, BufStream};
+
+use super::{ParsingRequestError, ParsingResponseError, RawRequest, RawResponse};
+
+/// Reads and writes frames on any async byte stream, like a TCP or Unix socket, a TLS stream or
+/// an in-memory [tokio::io::duplex] pipe. Reads and writes are buffered, and every write is
+/// flushed.
+pub struct Connection<S> {
+  stream: BufStream<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+  pub fn new(stream: S) -> Self {
+    Connection { stream: BufStream::new(stream) }
+  }
+
+  pub async fn read_request(&mut self) -> Result<RawRequest, ParsingRequestError> {
+    RawRequest::read_from(&mut self.stream).await
+  }
+
+  pub async fn write_request(&mut self, request: &RawRequest) -> io::Result<()> {
+    request.write_to(&mut self.stream).await?;
+    self.stream.flush().await
+  }
+
+  pub async fn read_response(&mut self) -> Result<RawResponse, ParsingResponseError> {
+    RawResponse::read_from(&mut self.stream).await
+  }
+
+  pub async fn write_response(&mut self, response: &RawResponse) -> io::Result<()> {
+    response.write_to(&mut self.stream).await?;
+    self.stream.flush().await
+  }
+
+  pub fn get_ref(&self) -> &S {
+    self.stream.get_ref()
+  }
+
+  /// Anything still buffered is lost.
+  pub fn into_inner(self) -> S {
+    self.stream.into_inner()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::{duplex, AsyncWriteExt as _};
+
+  use super::*;
+  use crate::tcp::protocol::{ResponseType, PROTOCOL_V0, PROTOCOL_V1};
+
+  #[tokio::test]
+  async fn frames_v0_and_v1() {
+    let (client, server) = duplex(1024);
+    let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+
+    client.write_request(&RawRequest::new(2, b"v0".to_vec())).await.unwrap();
+    client.write_request(&RawRequest::new_v1(7, 3, b"v1".to_vec())).await.unwrap();
+
+    let v0 = server.read_request().await.unwrap();
+    assert_eq!(
+      (v0.version, v0.command, v0.id, v0.body.as_slice()),
+      (PROTOCOL_V0, 2, 0, &b"v0"[..])
+    );
+    let v1 = server.read_request().await.unwrap();
+    assert_eq!(
+      (v1.version, v1.command, v1.id, v1.body.as_slice()),
+      (PROTOCOL_V1, 3, 7, &b"v1"[..])
+    );
+
+    let response =
+      RawResponse::new(ResponseType::Ok.into(), b"ok".to_vec()).for_request(PROTOCOL_V1, 7);
+    server.write_response(&response).await.unwrap();
+    let response = client.read_response().await.unwrap();
+    assert_eq!(
+      (response.version, response.id, response.body.as_slice()),
+      (PROTOCOL_V1, 7, &b"ok"[..])
+    );
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_frames() {
+    // A version 0 frame can't carry more than u16::MAX bytes.
+    let (client, _server) = duplex(1024);
+    let large = RawRequest::new(2, vec![0; u16::MAX as usize + 1]);
+    assert!(Connection::new(client).write_request(&large).await.is_err());
+
+    let (mut client, server) = duplex(1024);
+    client.write_all(&[9, 1, 0, 0]).await.unwrap();
+    let read = Connection::new(server).read_request().await;
+    assert!(matches!(read, Err(ParsingRequestError::UnsupportedVersion(9))));
+
+    // The body is shorter than the header claims.
+    let (mut client, server) = duplex(1024);
+    client.write_all(&[PROTOCOL_V0, 1, 0, 5, b'a']).await.unwrap();
+    drop(client);
+    let read = Connection::new(server).read_request().await;
+    assert!(matches!(read, Err(ParsingRequestError::BodylengthMissmatch)));
+
+    let (client, server) = duplex(1024);
+    drop(client);
+    let read = Connection::new(server).read_request().await;
+    assert!(matches!(read, Err(ParsingRequestError::ConnectionClosed)));
+  }
+}
@@ -1,5 +1,11 @@
+mod connection;
+pub use connection::*;
+
 mod request;
 pub use request::*;
 
 mod response;
 pub use response::*;
+
+mod version;
+pub use version::*;
@@ -1,13 +1,21 @@
-use std::io;
+use std::io::{self, Read, Write};
 
-use tokio::{
-  io::{AsyncReadExt as _, AsyncWriteExt as _},
-  net::TcpStream,
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
+
+use super::{
+  encode_frame, read_frame, read_frame_blocking, Frame, FrameError, FramePart, PROTOCOL_V0,
+  PROTOCOL_V1,
 };
+
 #[derive(Debug)]
 pub enum ParsingRequestError {
+  /// The peer closed the connection before sending another request.
+  ConnectionClosed,
   InvalidVersion,
+  UnsupportedVersion(u8),
+  /// The header after the version byte could not be read.
   InvalidCommand,
+  BodyTooLarge,
   BodylengthMissmatch,
 }
 
@@ -17,37 +25,64 @@ pub enum ParsingRequestError {
 pub struct RawRequest {
   pub version: u8,
   pub command: u8,
+  /// Correlation id echoed in the response. Version 0 has no id on the wire, so it is always 0.
+  pub id: u32,
   pub body: Vec<u8>,
 }
 
 impl RawRequest {
   pub fn new(command: u8, body: Vec<u8>) -> Self {
-    Self { command, body, version: 0 }
+    Self { command, body, version: PROTOCOL_V0, id: 0 }
+  }
+
+  pub fn new_v1(id: u32, command: u8, body: Vec<u8>) -> Self {
+    Self { command, body, version: PROTOCOL_V1, id }
   }
-  pub async fn write_to_tcp_stream(self, tcp_stream: &mut TcpStream) -> io::Result<()> {
-    let mut to_write = Vec::new();
 
-    to_write.push(self.version);
-    to_write.push(self.command);
-    to_write.extend((self.body.len() as u16).to_be_bytes());
-    to_write.extend(self.body);
+  pub async fn write_to<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+    let to_write = encode_frame(self.version, self.command, self.id, &self.body)?;
 
-    tcp_stream.write_all(&to_write).await?;
+    stream.write_all(&to_write).await?;
 
     Ok(())
   }
 
-  pub async fn from_tcp_stream(tcp_stream: &mut TcpStream) -> Result<Self, ParsingRequestError> {
-    let version = tcp_stream.read_u8().await.map_err(|_| ParsingRequestError::InvalidVersion)?;
-    let command = tcp_stream.read_u8().await.map_err(|_| ParsingRequestError::InvalidCommand)?;
+  /// Blocking version of [RawRequest::write_to].
+  pub fn write_to_blocking<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+    let to_write = encode_frame(self.version, self.command, self.id, &self.body)?;
 
-    let body_len = tcp_stream.read_u16().await.map_err(|_| ParsingRequestError::InvalidCommand)?;
+    stream.write_all(&to_write)
+  }
 
-    let mut body: Vec<u8> = vec![0u8; body_len.into()];
-    tcp_stream.read_exact(&mut body).await.map_err(|_| ParsingRequestError::BodylengthMissmatch)?;
+  pub async fn read_from<R: AsyncRead + Unpin>(
+    stream: &mut R,
+  ) -> Result<Self, ParsingRequestError> {
+    Ok(read_frame(stream).await?.into())
+  }
 
-    let raw_req = RawRequest { version, command, body };
+  /// Blocking version of [RawRequest::read_from].
+  pub fn read_from_blocking<R: Read>(stream: &mut R) -> Result<Self, ParsingRequestError> {
+    Ok(read_frame_blocking(stream)?.into())
+  }
+}
+
+impl From<Frame> for RawRequest {
+  fn from(frame: Frame) -> Self {
+    RawRequest { version: frame.version, command: frame.kind, id: frame.id, body: frame.body }
+  }
+}
 
-    Ok(raw_req)
+impl From<FrameError> for ParsingRequestError {
+  fn from(err: FrameError) -> Self {
+    match err {
+      FrameError::Read(FramePart::Version, err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+        ParsingRequestError::ConnectionClosed
+      }
+      FrameError::Read(FramePart::Version, _) => ParsingRequestError::InvalidVersion,
+      FrameError::Read(FramePart::Header, _) => ParsingRequestError::InvalidCommand,
+      FrameError::Read(FramePart::Body, _) => ParsingRequestError::BodylengthMissmatch,
+      FrameError::UnsupportedVersion(version) => ParsingRequestError::UnsupportedVersion(version),
+      FrameError::BodyTooLarge => ParsingRequestError::BodyTooLarge,
+    }
   }
 }
@@ -1,7 +1,75 @@
-use std::io;
+use std::io::{self, Read, Write};
 
-use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
-use tokio::net::TcpStream;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
+
+use super::{
+  encode_frame, read_frame, read_frame_blocking, Frame, FrameError, FramePart, PROTOCOL_V0,
+};
+
+/// Value of [RawResponse::type]. Everything except [ResponseType::Ok] is an error, and the
+/// body of an error response is a bincode encoded [ErrorBody].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ResponseType {
+  /// 0
+  Ok = 0,
+  /// 1: The requested key does not exist.
+  NotFound = 1,
+  /// 2: The request was framed correctly, but its body could not be decoded.
+  BadRequest = 2,
+  /// 3: The command byte is not a known command.
+  UnknownCommand = 3,
+  /// 4: The request could not be read from the stream. The connection is closed afterwards.
+  MalformedRequest = 4,
+  /// 5: The request was valid, but the server failed to execute it.
+  InternalError = 5,
+  /// 6: The version byte is not a supported protocol version. The connection is closed
+  /// afterwards, and the response is framed as version 0.
+  UnsupportedVersion = 6,
+  /// 7: The response body doesn't fit in the frame of the request's protocol version.
+  PayloadTooLarge = 7,
+  /// 8: The `if_version` condition of a write didn't hold, so nothing was written.
+  ConditionFailed = 8,
+  /// 9: Authentication is required, send an Auth request first. Also sent when Auth fails.
+  Unauthenticated = 9,
+  /// 10: The authenticated user isn't allowed to run the command or access the key.
+  Forbidden = 10,
+}
+
+impl From<ResponseType> for u8 {
+  fn from(value: ResponseType) -> Self {
+    value as u8
+  }
+}
+
+impl TryFrom<u8> for ResponseType {
+  type Error = ();
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    let res = match value {
+      0 => ResponseType::Ok,
+      1 => ResponseType::NotFound,
+      2 => ResponseType::BadRequest,
+      3 => ResponseType::UnknownCommand,
+      4 => ResponseType::MalformedRequest,
+      5 => ResponseType::InternalError,
+      6 => ResponseType::UnsupportedVersion,
+      7 => ResponseType::PayloadTooLarge,
+      8 => ResponseType::ConditionFailed,
+      9 => ResponseType::Unauthenticated,
+      10 => ResponseType::Forbidden,
+      _ => return Err(()),
+    };
+
+    Ok(res)
+  }
+}
+
+/// Body of every response whose [ResponseType] is not [ResponseType::Ok].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+  pub message: String,
+}
 
 /// This is the raw request that is received from tcp. This doesn't change based on request
 /// versions, since it operates with raw bytes. This acts as the first formating.
@@ -9,52 +77,97 @@ use tokio::net::TcpStream;
 pub struct RawResponse {
   pub version: u8,
   pub r#type: u8,
+  /// Id of the request this responds to. Always 0 in protocol version 0.
+  pub id: u32,
   pub body: Vec<u8>,
 }
 
 impl RawResponse {
   pub fn new(r#type: u8, body: Vec<u8>) -> Self {
     RawResponse {
-      version: 0,
+      version: PROTOCOL_V0,
       r#type,
+      id: 0,
       //body_len: body.len() as u16,
       body,
     }
   }
-  pub async fn write_to_tcp_stream(self, mut tcp_stream: TcpStream) -> io::Result<()> {
-    let mut to_write = Vec::new();
 
-    to_write.push(self.version);
-    to_write.push(self.r#type);
-    to_write.extend((self.body.len() as u16).to_be_bytes());
-    to_write.extend(self.body);
+  /// Frames the response in the protocol version of the request and echoes its id.
+  pub fn for_request(mut self, version: u8, id: u32) -> Self {
+    self.version = version;
+    self.id = id;
+    self
+  }
+
+  pub fn error(r#type: ResponseType, message: impl Into<String>) -> Self {
+    let body = ErrorBody { message: message.into() };
+    RawResponse::new(r#type.into(), bincode::serialize(&body).unwrap_or_default())
+  }
+  pub async fn write_to<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+    let to_write = encode_frame(self.version, self.r#type, self.id, &self.body)?;
 
-    tcp_stream.write_all(&to_write).await?;
+    stream.write_all(&to_write).await?;
 
     Ok(())
   }
 
-  pub async fn from_tcp_stream(tcp_stream: &mut TcpStream) -> Result<Self, ParsingResponseError> {
-    let version = tcp_stream.read_u8().await.map_err(|_| ParsingResponseError::InvalidVersion)?;
-    let r#type = tcp_stream.read_u8().await.map_err(|_| ParsingResponseError::InvalidType)?;
+  /// Blocking version of [RawResponse::write_to].
+  pub fn write_to_blocking<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+    let to_write = encode_frame(self.version, self.r#type, self.id, &self.body)?;
 
-    let body_len = tcp_stream.read_u16().await.map_err(|_| ParsingResponseError::InvalidBodyLen)?;
+    stream.write_all(&to_write)
+  }
 
-    let mut body: Vec<u8> = vec![0u8; body_len.into()];
-    tcp_stream
-      .read_exact(&mut body)
-      .await
-      .map_err(|_| ParsingResponseError::BodylengthMissmatch)?;
+  pub async fn read_from<R: AsyncRead + Unpin>(
+    stream: &mut R,
+  ) -> Result<Self, ParsingResponseError> {
+    Ok(read_frame(stream).await?.into())
+  }
 
-    let raw_req = RawResponse { version, r#type, body };
+  /// Blocking version of [RawResponse::read_from].
+  pub fn read_from_blocking<R: Read>(stream: &mut R) -> Result<Self, ParsingResponseError> {
+    Ok(read_frame_blocking(stream)?.into())
+  }
+}
 
-    Ok(raw_req)
+impl From<Frame> for RawResponse {
+  fn from(frame: Frame) -> Self {
+    RawResponse { version: frame.version, r#type: frame.kind, id: frame.id, body: frame.body }
+  }
+}
+
+impl From<FrameError> for ParsingResponseError {
+  fn from(err: FrameError) -> Self {
+    match err {
+      // Keeps timeouts of blocking streams apart from protocol errors, wherever in the frame
+      // they hit.
+      FrameError::Read(_, err)
+        if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
+      {
+        ParsingResponseError::TimedOut
+      }
+      FrameError::Read(FramePart::Version, err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+        ParsingResponseError::ConnectionClosed
+      }
+      FrameError::Read(FramePart::Version, _) | FrameError::UnsupportedVersion(_) => {
+        ParsingResponseError::InvalidVersion
+      }
+      FrameError::Read(FramePart::Header, _) => ParsingResponseError::InvalidType,
+      FrameError::Read(FramePart::Body, _) => ParsingResponseError::BodylengthMissmatch,
+      FrameError::BodyTooLarge => ParsingResponseError::InvalidBodyLen,
+    }
   }
 }
 
 #[derive(Debug)]
 pub enum ParsingResponseError {
+  /// The peer closed the connection before sending a response.
+  ConnectionClosed,
+  /// A read timeout of a blocking stream expired before the response arrived.
+  TimedOut,
   InvalidVersion,
+  /// The header after the version byte could not be read.
   InvalidType,
 
   InvalidBodyLen,
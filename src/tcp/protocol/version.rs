@@ -0,0 +1,140 @@
+use std::io::{self, Read};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+/// Version 0 frames are `[version: u8][command | type: u8][body length: u16][body]`.
+pub const PROTOCOL_V0: u8 = 0;
+
+/// Version 1 frames are `[version: u8][command | type: u8][id: u32][body length: u32][body]`.
+/// The id is picked by the client and echoed in the response, so pipelined responses can be
+/// matched to their requests.
+pub const PROTOCOL_V1: u8 = 1;
+
+/// Largest body accepted in a version 1 frame. Guards against allocating whatever length a
+/// broken client sends.
+pub const MAX_BODY_LEN_V1: usize = 64 * 1024 * 1024;
+
+/// Largest body the given protocol version can carry, or [None] for unknown versions.
+pub fn max_body_len(version: u8) -> Option<usize> {
+  match version {
+    PROTOCOL_V0 => Some(u16::MAX as usize),
+    PROTOCOL_V1 => Some(MAX_BODY_LEN_V1),
+    _ => None,
+  }
+}
+
+/// Encodes a whole frame for both requests and responses, since they share the same layout.
+pub(crate) fn encode_frame(version: u8, kind: u8, id: u32, body: &[u8]) -> io::Result<Vec<u8>> {
+  let Some(max_len) = max_body_len(version) else {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unknown protocol version"));
+  };
+  if body.len() > max_len {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Body too large for protocol version"));
+  }
+
+  let mut to_write = Vec::with_capacity(body.len() + 10);
+
+  to_write.push(version);
+  to_write.push(kind);
+  if version == PROTOCOL_V0 {
+    to_write.extend((body.len() as u16).to_be_bytes());
+  } else {
+    to_write.extend(id.to_be_bytes());
+    to_write.extend((body.len() as u32).to_be_bytes());
+  }
+  to_write.extend(body);
+
+  Ok(to_write)
+}
+
+/// A frame as read from the stream, before it is turned into a request or response.
+pub(crate) struct Frame {
+  pub version: u8,
+  pub kind: u8,
+  pub id: u32,
+  pub body: Vec<u8>,
+}
+
+/// The part of a frame reading failed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FramePart {
+  Version,
+  Header,
+  Body,
+}
+
+pub(crate) enum FrameError {
+  Read(FramePart, io::Error),
+  UnsupportedVersion(u8),
+  BodyTooLarge,
+}
+
+/// The fields of a frame between the version byte and the body.
+struct FrameHeader {
+  kind: u8,
+  id: u32,
+  body_len: usize,
+}
+
+/// Length of the [FrameHeader] of `version`.
+fn header_len(version: u8) -> Result<usize, FrameError> {
+  match version {
+    PROTOCOL_V0 => Ok(3),
+    PROTOCOL_V1 => Ok(9),
+    _ => Err(FrameError::UnsupportedVersion(version)),
+  }
+}
+
+/// Decodes the [header_len] bytes following the version byte, and checks the body fits in a
+/// frame of `version`.
+fn decode_header(version: u8, header: &[u8]) -> Result<FrameHeader, FrameError> {
+  let kind = header[0];
+  let header = if version == PROTOCOL_V0 {
+    let body_len = u16::from_be_bytes([header[1], header[2]]) as usize;
+    FrameHeader { kind, id: 0, body_len }
+  } else {
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let body_len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    FrameHeader { kind, id, body_len }
+  };
+
+  match max_body_len(version) {
+    Some(max_len) if header.body_len <= max_len => Ok(header),
+    Some(_) => Err(FrameError::BodyTooLarge),
+    None => Err(FrameError::UnsupportedVersion(version)),
+  }
+}
+
+/// Reads one frame of any supported version. Requests and responses are both read with this.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Frame, FrameError> {
+  let read = |part| move |err| FrameError::Read(part, err);
+
+  let version = stream.read_u8().await.map_err(read(FramePart::Version))?;
+  let mut header = [0u8; 9];
+  let header = &mut header[..header_len(version)?];
+  stream.read_exact(header).await.map_err(read(FramePart::Header))?;
+  let header = decode_header(version, header)?;
+
+  let mut body = vec![0u8; header.body_len];
+  stream.read_exact(&mut body).await.map_err(read(FramePart::Body))?;
+
+  Ok(Frame { version, kind: header.kind, id: header.id, body })
+}
+
+/// Blocking version of [read_frame].
+pub(crate) fn read_frame_blocking<R: Read>(stream: &mut R) -> Result<Frame, FrameError> {
+  let read = |part| move |err| FrameError::Read(part, err);
+
+  let mut version = [0u8];
+  stream.read_exact(&mut version).map_err(read(FramePart::Version))?;
+  let [version] = version;
+  let mut header = [0u8; 9];
+  let header = &mut header[..header_len(version)?];
+  stream.read_exact(header).map_err(read(FramePart::Header))?;
+  let header = decode_header(version, header)?;
+
+  let mut body = vec![0u8; header.body_len];
+  stream.read_exact(&mut body).map_err(read(FramePart::Body))?;
+
+  Ok(Frame { version, kind: header.kind, id: header.id, body })
+}
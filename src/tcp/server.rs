@@ -1,14 +1,39 @@
-use tokio::net::{TcpListener, TcpStream};
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::TcpListener,
+  sync::{OwnedSemaphorePermit, Semaphore},
+  task,
+  time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
-  public_api::dataquery::DataQuery,
-  state::State,
-  tcp::protocol::{RawRequest, RawResponse},
+  config::Config,
+  public_api::{
+    auth::{AuthQuery, AuthResponse},
+    dataquery::{DataQuery, QueryError},
+  },
+  state::{Session, State},
+  tcp::protocol::{
+    max_body_len, Connection, ParsingRequestError, RawRequest, RawResponse, ResponseType,
+  },
 };
 
+/// Upper bound of connections served at the same time, unless configured otherwise with
+/// [TcpServer::with_max_connections].
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Clients that don't finish the TLS handshake in time are disconnected, so they can't hold on
+/// to a connection slot.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct TcpServer {
   address: String,
   state: State,
+  max_connections: usize,
+  tls: Option<TlsAcceptor>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -21,6 +46,35 @@ pub enum CommandV0 {
   Put,
   /// 3
   Delete,
+  /// 4
+  CompareAndSwap,
+  /// 5
+  Transaction,
+  /// 6
+  MultiGet,
+  /// 7
+  MultiPut,
+  /// 8
+  MultiDelete,
+  /// 9
+  Scan,
+  /// 10
+  Auth,
+  /// 11
+  ///
+  /// Put with an expiry or version condition. [CommandV0::Put] takes a plain key and value, like
+  /// it always did.
+  PutWithOptions,
+  /// 12
+  ///
+  /// Delete with a version condition.
+  DeleteWithOptions,
+}
+
+impl From<CommandV0> for u8 {
+  fn from(value: CommandV0) -> Self {
+    value as u8
+  }
 }
 
 impl TryFrom<u8> for CommandV0 {
@@ -31,6 +85,15 @@ impl TryFrom<u8> for CommandV0 {
       1 => CommandV0::Get,
       2 => CommandV0::Put,
       3 => CommandV0::Delete,
+      4 => CommandV0::CompareAndSwap,
+      5 => CommandV0::Transaction,
+      6 => CommandV0::MultiGet,
+      7 => CommandV0::MultiPut,
+      8 => CommandV0::MultiDelete,
+      9 => CommandV0::Scan,
+      10 => CommandV0::Auth,
+      11 => CommandV0::PutWithOptions,
+      12 => CommandV0::DeleteWithOptions,
       _ => return Err(()),
     };
 
@@ -40,18 +103,147 @@ impl TryFrom<u8> for CommandV0 {
 
 impl TcpServer {
   pub fn new(address: &str, state: State) -> Self {
-    TcpServer { address: address.to_string(), state }
+    TcpServer {
+      address: address.to_string(),
+      state,
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      tls: None,
+    }
+  }
+
+  /// Server for the listen address, connection limit and TLS settings of `config`.
+  pub fn from_config(config: &Config, state: State) -> io::Result<Self> {
+    let server = TcpServer::new(&config.listen, state).with_max_connections(config.max_connections);
+    match &config.tls {
+      Some(tls) => Ok(server.with_tls(tls.acceptor()?)),
+      None => Ok(server),
+    }
+  }
+
+  /// Limits how many connections are handled concurrently. When the limit is reached the
+  /// server stops accepting, so further clients wait in the listen backlog until a slot frees up.
+  pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+    self.max_connections = max_connections;
+    self
+  }
+
+  /// Only accepts TLS connections, see [crate::tcp::tls::ServerTlsConfig::acceptor].
+  pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+    self.tls = Some(acceptor);
+    self
   }
 
-  pub async fn handle_conn(&mut self, mut stream: TcpStream) {
-    let req = RawRequest::from_tcp_stream(&mut stream).await.unwrap();
+  /// Serves requests on the connection until the client hangs up. Requests are handled one
+  /// after another, so clients can pipeline requests and get the responses back in order.
+  ///
+  /// Works on any stream, so other transports can reuse it.
+  pub async fn handle_conn<S: AsyncRead + AsyncWrite + Unpin>(mut state: State, stream: S) {
+    let mut conn = Connection::new(stream);
+    let mut session = Session::default();
 
-    let cmd: CommandV0 = CommandV0::try_from(req.command).unwrap();
-    let data_query: DataQuery = DataQuery::try_from((cmd, req.body)).unwrap();
+    loop {
+      let req = match conn.read_request().await {
+        Ok(req) => req,
+        Err(ParsingRequestError::ConnectionClosed) => return,
+        Err(err) => {
+          // The stream can't be trusted to be at a request boundary anymore, so this is the last
+          // response on this connection. It is framed as version 0, which every client can read.
+          tracing::debug!("Closing connection, could not read request: {:?}", err);
+          let response = match err {
+            ParsingRequestError::UnsupportedVersion(version) => RawResponse::error(
+              ResponseType::UnsupportedVersion,
+              format!("Unsupported protocol version {version}"),
+            ),
+            err => RawResponse::error(ResponseType::MalformedRequest, format!("{:?}", err)),
+          };
+          let _ = conn.write_response(&response).await;
+          return;
+        }
+      };
+
+      let (version, id) = (req.version, req.id);
+      let mut response = TcpServer::handle_request(&mut state, &mut session, req).await;
 
-    let response_bytes = self.state.handle_query(data_query).await;
-    let response = RawResponse::new(0, response_bytes);
-    response.write_to_tcp_stream(stream).await.unwrap();
+      if max_body_len(version).is_some_and(|max_len| response.body.len() > max_len) {
+        response = RawResponse::error(
+          ResponseType::PayloadTooLarge,
+          format!("Response does not fit in a protocol version {version} frame"),
+        );
+      }
+      let response = response.for_request(version, id);
+
+      if let Err(err) = conn.write_response(&response).await {
+        tracing::debug!("Closing connection, could not write response: {:?}", err);
+        return;
+      }
+    }
+  }
+
+  async fn handle_request(
+    state: &mut State,
+    session: &mut Session,
+    req: RawRequest,
+  ) -> RawResponse {
+    let Ok(cmd) = CommandV0::try_from(req.command) else {
+      return RawResponse::error(
+        ResponseType::UnknownCommand,
+        format!("Unknown command {}", req.command),
+      );
+    };
+
+    // Ping works without authentication, so it can be used as a health check.
+    if let CommandV0::Ping = cmd {
+      return match bincode::serialize(&state.node.ping()) {
+        Ok(body) => RawResponse::new(ResponseType::Ok.into(), body),
+        Err(err) => RawResponse::error(ResponseType::InternalError, err.to_string()),
+      };
+    }
+
+    if let CommandV0::Auth = cmd {
+      let Ok(query) = bincode::deserialize::<AuthQuery>(&req.body) else {
+        return RawResponse::error(ResponseType::BadRequest, "Invalid body for command Auth");
+      };
+
+      // A failed attempt logs out the previous user.
+      *session = Session::default();
+      return match state.authenticate(query).await {
+        Ok(authenticated) => {
+          let response = AuthResponse { user: authenticated.user.clone().unwrap_or_default() };
+          *session = authenticated;
+          match bincode::serialize(&response) {
+            Ok(body) => RawResponse::new(ResponseType::Ok.into(), body),
+            Err(err) => RawResponse::error(ResponseType::InternalError, err.to_string()),
+          }
+        }
+        Err(err) => TcpServer::error_response(err),
+      };
+    }
+
+    let Ok(data_query) = DataQuery::try_from((cmd, req.body)) else {
+      return RawResponse::error(
+        ResponseType::BadRequest,
+        format!("Invalid body for command {:?}", cmd),
+      );
+    };
+
+    match state.handle_query(data_query, session).await {
+      Ok(response_bytes) => RawResponse::new(ResponseType::Ok.into(), response_bytes),
+      Err(err) => TcpServer::error_response(err),
+    }
+  }
+
+  fn error_response(err: QueryError) -> RawResponse {
+    match err {
+      QueryError::NotFound => RawResponse::error(ResponseType::NotFound, "Key not found"),
+      QueryError::ConditionFailed => {
+        RawResponse::error(ResponseType::ConditionFailed, "Version condition did not hold")
+      }
+      QueryError::Internal(message) => RawResponse::error(ResponseType::InternalError, message),
+      QueryError::Unauthenticated => {
+        RawResponse::error(ResponseType::Unauthenticated, "Authentication required")
+      }
+      QueryError::Forbidden(message) => RawResponse::error(ResponseType::Forbidden, message),
+    }
   }
 
   pub async fn run(&mut self) {
@@ -61,13 +253,102 @@ impl TcpServer {
 
     tracing::info!("Server running on {}", self.address);
 
+    let connection_slots = Arc::new(Semaphore::new(self.max_connections));
+
     loop {
+      let permit = connection_slot(&connection_slots, self.max_connections).await;
+
       match listener.accept().await {
-        Ok((stream, _)) => self.handle_conn(stream).await,
+        Ok((stream, _)) => {
+          if let Err(err) = stream.set_nodelay(true) {
+            tracing::warn!("Could not set TCP_NODELAY: {:?}", err);
+          }
+          let state = self.state.clone();
+          let tls = self.tls.clone();
+          task::spawn(async move {
+            match tls {
+              Some(acceptor) => match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+              {
+                Ok(Ok(stream)) => TcpServer::handle_conn(state, stream).await,
+                Ok(Err(err)) => tracing::debug!("TLS handshake failed: {:?}", err),
+                Err(_) => tracing::debug!("TLS handshake timed out"),
+              },
+              None => TcpServer::handle_conn(state, stream).await,
+            }
+            drop(permit);
+          });
+        }
         Err(e) => {
-          eprintln!("Connection failed: {}", e);
+          tracing::error!("Connection failed: {}", e);
         }
       };
     }
   }
 }
+
+/// Waits for a free connection slot. Listeners call this before accepting, so a full server
+/// pushes back on new clients instead of piling up tasks.
+pub(crate) async fn connection_slot(
+  slots: &Arc<Semaphore>,
+  max_connections: usize,
+) -> OwnedSemaphorePermit {
+  match slots.clone().try_acquire_owned() {
+    Ok(permit) => permit,
+    Err(_) => {
+      tracing::warn!("Connection limit of {} reached, waiting", max_connections);
+      slots.clone().acquire_owned().await.expect("Semaphore is never closed")
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::{duplex, AsyncWriteExt as _};
+
+  use super::*;
+  use crate::{
+    public_api::ping::PingResponse,
+    tcp::protocol::{ErrorBody, ParsingResponseError, PROTOCOL_V0, PROTOCOL_V1},
+  };
+
+  #[tokio::test]
+  async fn answers_in_the_version_of_the_request() {
+    let (client, server) = duplex(4096);
+    tokio::spawn(TcpServer::handle_conn(State::default(), server));
+    let mut client = Connection::new(client);
+
+    for request in [
+      RawRequest::new(CommandV0::Ping.into(), vec![]),
+      RawRequest::new_v1(42, CommandV0::Ping.into(), vec![]),
+    ] {
+      client.write_request(&request).await.unwrap();
+      let response = client.read_response().await.unwrap();
+      assert_eq!((response.version, response.id), (request.version, request.id));
+      assert_eq!(response.r#type, u8::from(ResponseType::Ok));
+      bincode::deserialize::<PingResponse>(&response.body).unwrap();
+    }
+
+    client.write_request(&RawRequest::new_v1(1, 200, vec![])).await.unwrap();
+    let response = client.read_response().await.unwrap();
+    assert_eq!(
+      (response.version, response.r#type),
+      (PROTOCOL_V1, u8::from(ResponseType::UnknownCommand))
+    );
+  }
+
+  #[tokio::test]
+  async fn closes_on_unsupported_versions() {
+    let (mut client, server) = duplex(4096);
+    tokio::spawn(TcpServer::handle_conn(State::default(), server));
+
+    // Clients refuse to frame an unknown version, so the bytes are written by hand.
+    client.write_all(&[9, CommandV0::Ping.into(), 0, 0]).await.unwrap();
+    let mut client = Connection::new(client);
+
+    let response = client.read_response().await.unwrap();
+    assert_eq!(response.version, PROTOCOL_V0);
+    assert_eq!(response.r#type, u8::from(ResponseType::UnsupportedVersion));
+    bincode::deserialize::<ErrorBody>(&response.body).unwrap();
+    assert!(matches!(client.read_response().await, Err(ParsingResponseError::ConnectionClosed)));
+  }
+}
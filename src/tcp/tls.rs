@@ -0,0 +1,221 @@
+use std::{fs::File, io, io::BufReader, path::PathBuf, sync::Arc};
+
+use serde::Deserialize;
+use tokio_rustls::{
+  rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+  },
+  TlsAcceptor,
+};
+
+/// Paths of the PEM files a server needs for TLS.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+  pub cert_path: PathBuf,
+  pub key_path: PathBuf,
+  /// When set, clients have to present a certificate signed by one of these CAs (mutual TLS).
+  pub client_ca_path: Option<PathBuf>,
+}
+
+impl ServerTlsConfig {
+  pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&self.cert_path)?;
+    let key = load_key(&self.key_path)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &self.client_ca_path {
+      Some(client_ca_path) => {
+        let roots = load_roots(client_ca_path)?;
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid)?;
+        builder.with_client_cert_verifier(verifier)
+      }
+      None => builder.with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(certs, key).map_err(invalid)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+  }
+}
+
+/// Paths of the PEM files a client needs for TLS.
+#[derive(Clone, Debug)]
+pub struct ClientTlsConfig {
+  /// CA certificates the server certificate is checked against. Use the server certificate
+  /// itself when it is self-signed.
+  pub ca_path: PathBuf,
+  /// Name the server certificate has to be issued for.
+  pub server_name: String,
+  /// Certificate and key to authenticate with, for servers that require mutual TLS.
+  pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+/// A loaded [ClientTlsConfig].
+#[derive(Clone, Debug)]
+pub struct ClientTls {
+  pub config: Arc<ClientConfig>,
+  pub server_name: ServerName<'static>,
+}
+
+impl ClientTlsConfig {
+  pub fn load(&self) -> io::Result<ClientTls> {
+    let roots = load_roots(&self.ca_path)?;
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    let config = match &self.client_cert {
+      Some((cert_path, key_path)) => builder
+        .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .map_err(invalid)?,
+      None => builder.with_no_client_auth(),
+    };
+    let server_name = ServerName::try_from(self.server_name.clone()).map_err(invalid)?;
+
+    Ok(ClientTls { config: Arc::new(config), server_name })
+  }
+}
+
+fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
+  let mut reader = BufReader::new(File::open(path)?);
+  rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_key(path: &PathBuf) -> io::Result<PrivateKeyDer<'static>> {
+  let mut reader = BufReader::new(File::open(path)?);
+  rustls_pemfile::private_key(&mut reader)?
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key found"))
+}
+
+fn load_roots(path: &PathBuf) -> io::Result<RootCertStore> {
+  let mut roots = RootCertStore::empty();
+  for cert in load_certs(path)? {
+    roots.add(cert).map_err(invalid)?;
+  }
+  Ok(roots)
+}
+
+fn invalid(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, path::Path};
+
+  use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+  use tokio::io::duplex;
+  use tokio_rustls::TlsConnector;
+
+  use super::*;
+  use crate::{
+    state::State,
+    tcp::{
+      protocol::{Connection, RawRequest, ResponseType},
+      server::{CommandV0, TcpServer},
+    },
+  };
+
+  struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+  }
+
+  impl Ca {
+    fn new() -> Self {
+      let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      let key = KeyPair::generate().unwrap();
+      Ca { cert: params.self_signed(&key).unwrap(), key }
+    }
+
+    /// Writes the CA certificate, and a certificate for `name` with its key, to `dir`. Returns
+    /// the paths of the three files.
+    fn issue(&self, dir: &Path, name: &str) -> (PathBuf, PathBuf, PathBuf) {
+      let key = KeyPair::generate().unwrap();
+      let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+      let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+      let paths = (
+        dir.join(format!("{name}-ca.pem")),
+        dir.join(format!("{name}.pem")),
+        dir.join(format!("{name}.key")),
+      );
+      fs::write(&paths.0, self.cert.pem()).unwrap();
+      fs::write(&paths.1, cert.pem()).unwrap();
+      fs::write(&paths.2, key.serialize_pem()).unwrap();
+      paths
+    }
+  }
+
+  /// Runs a server over an in-memory pipe and pings it with a client using `client`.
+  async fn ping(server: &ServerTlsConfig, client: &ClientTlsConfig) -> io::Result<()> {
+    let (acceptor, client) = (server.acceptor()?, client.load()?);
+    let (client_stream, server_stream) = duplex(16 * 1024);
+
+    let server = tokio::spawn(async move {
+      let stream = acceptor.accept(server_stream).await?;
+      TcpServer::handle_conn(State::default(), stream).await;
+      Ok::<_, io::Error>(())
+    });
+
+    let connector = TlsConnector::from(client.config);
+    let stream = connector.connect(client.server_name, client_stream).await?;
+    let mut conn = Connection::new(stream);
+    conn.write_request(&RawRequest::new_v1(1, CommandV0::Ping.into(), vec![])).await?;
+    let response = conn.read_response().await.map_err(|err| io::Error::other(format!("{err:?}")));
+    drop(conn);
+
+    server.await.unwrap()?;
+    assert_eq!(response?.r#type, u8::from(ResponseType::Ok));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn serves_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let (ca_path, cert_path, key_path) = Ca::new().issue(dir.path(), "localhost");
+
+    let server = ServerTlsConfig { cert_path, key_path, client_ca_path: None };
+    let client = ClientTlsConfig {
+      ca_path: ca_path.clone(),
+      server_name: "localhost".to_string(),
+      client_cert: None,
+    };
+    ping(&server, &client).await.unwrap();
+
+    // The certificate isn't issued for this name.
+    let client = ClientTlsConfig { server_name: "example.com".to_string(), ..client };
+    assert!(ping(&server, &client).await.is_err());
+
+    // Nor signed by this CA.
+    let (other_ca_path, ..) = Ca::new().issue(dir.path(), "other");
+    let client = ClientTlsConfig {
+      ca_path: other_ca_path,
+      server_name: "localhost".to_string(),
+      client_cert: None,
+    };
+    assert!(ping(&server, &client).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn requires_client_certificates_with_mutual_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let (ca_path, cert_path, key_path) = Ca::new().issue(dir.path(), "localhost");
+    let client_ca = Ca::new();
+    let (client_ca_path, client_cert, client_key) = client_ca.issue(dir.path(), "client");
+
+    let server = ServerTlsConfig { cert_path, key_path, client_ca_path: Some(client_ca_path) };
+    let client =
+      ClientTlsConfig { ca_path, server_name: "localhost".to_string(), client_cert: None };
+    assert!(ping(&server, &client).await.is_err());
+
+    // Signed by a CA the server doesn't trust.
+    let (_, other_cert, other_key) = Ca::new().issue(dir.path(), "other");
+    let client = ClientTlsConfig { client_cert: Some((other_cert, other_key)), ..client };
+    assert!(ping(&server, &client).await.is_err());
+
+    let client = ClientTlsConfig { client_cert: Some((client_cert, client_key)), ..client };
+    ping(&server, &client).await.unwrap();
+  }
+}
@@ -0,0 +1,166 @@
+use std::{
+  fs, io,
+  os::unix::{
+    fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _},
+    net::UnixStream,
+  },
+  path::PathBuf,
+  sync::Arc,
+};
+
+use tokio::{net::UnixListener, sync::Semaphore, task};
+
+use crate::{
+  state::State,
+  tcp::server::{connection_slot, TcpServer, DEFAULT_MAX_CONNECTIONS},
+};
+
+/// Serves the same protocol as [TcpServer] on a Unix domain socket, for clients on the same
+/// host. Who may connect is controlled by the permissions of the socket file.
+pub struct UnixServer {
+  path: PathBuf,
+  state: State,
+  max_connections: usize,
+  permissions: Option<u32>,
+}
+
+impl UnixServer {
+  pub fn new(path: impl Into<PathBuf>, state: State) -> Self {
+    UnixServer {
+      path: path.into(),
+      state,
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+      permissions: None,
+    }
+  }
+
+  /// See [TcpServer::with_max_connections].
+  pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+    self.max_connections = max_connections;
+    self
+  }
+
+  /// Mode of the socket file, e.g. `0o660` to only let the owner and its group connect.
+  /// Without it, the socket is created according to the umask.
+  pub fn with_permissions(mut self, mode: u32) -> Self {
+    self.permissions = Some(mode);
+    self
+  }
+
+  /// Binds the socket. A socket file left over from an earlier run is replaced, but not one
+  /// another server still listens on.
+  fn bind(&self) -> io::Result<UnixListener> {
+    self.remove_stale_socket()?;
+
+    let Some(mode) = self.permissions else {
+      return UnixListener::bind(&self.path);
+    };
+
+    // The socket is bound in a directory only the owner can enter and moved into place once it
+    // has its mode, so nobody can connect while it still has the mode of the umask.
+    let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = self.path.with_file_name(format!(".{}.{}", file_name, std::process::id()));
+    // Left over if an earlier process with the same pid died while binding.
+    match fs::remove_dir_all(&dir) {
+      Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+      _ => {}
+    }
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private = dir.join("socket");
+    let listener = UnixListener::bind(&private).and_then(|listener| {
+      fs::set_permissions(&private, fs::Permissions::from_mode(mode))?;
+      fs::rename(&private, &self.path)?;
+      Ok(listener)
+    });
+    let _ = fs::remove_file(&private);
+    fs::remove_dir(&dir)?;
+    listener
+  }
+
+  fn remove_stale_socket(&self) -> io::Result<()> {
+    match fs::symlink_metadata(&self.path) {
+      Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(&self.path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "Another server uses the socket")),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(&self.path),
+        Err(err) => Err(err),
+      },
+      Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, "Path exists and is no socket")),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+      Err(err) => Err(err),
+    }
+  }
+
+  pub async fn run(&mut self) {
+    let listener =
+      self.bind().unwrap_or_else(|err| panic!("Could not bind to socket {:?}: {}", self.path, err));
+
+    tracing::info!("Server running on {:?}", self.path);
+
+    let connection_slots = Arc::new(Semaphore::new(self.max_connections));
+
+    loop {
+      let permit = connection_slot(&connection_slots, self.max_connections).await;
+
+      match listener.accept().await {
+        Ok((stream, _)) => {
+          let state = self.state.clone();
+          task::spawn(async move {
+            TcpServer::handle_conn(state, stream).await;
+            drop(permit);
+          });
+        }
+        Err(e) => {
+          tracing::error!("Connection failed: {}", e);
+        }
+      };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn server(path: &std::path::Path) -> UnixServer {
+    UnixServer::new(path, State::default())
+  }
+
+  #[tokio::test]
+  async fn binds_with_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.sock");
+    // A private dir left over by a crashed process with the same pid.
+    let leftover = dir.path().join(format!(".db.sock.{}", std::process::id()));
+    fs::create_dir(&leftover).unwrap();
+    fs::write(leftover.join("socket"), b"").unwrap();
+
+    let _listener = server(&path).with_permissions(0o640).bind().unwrap();
+
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+  }
+
+  #[tokio::test]
+  async fn replaces_stale_sockets() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.sock");
+    // Dropping the listener leaves the socket file behind, like a crashed server does.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let _listener = server(&path).bind().unwrap();
+    UnixStream::connect(&path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn refuses_sockets_of_live_servers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.sock");
+    let _live = server(&path).bind().unwrap();
+
+    for server in [server(&path), server(&path).with_permissions(0o600)] {
+      assert_eq!(server.bind().unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+    UnixStream::connect(&path).unwrap();
+  }
+}